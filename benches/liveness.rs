@@ -0,0 +1,54 @@
+/*
+how long it takes to check whether a state's lock is held by a live process
+
+`sysinfo_scan` is what every lock check used to cost (listing every process on the system);
+the other benchmarks are full loads of a state whose lockfile needs checking.
+Run with `cargo bench --bench liveness`.
+*/
+
+use std::fs::{remove_dir_all, write};
+use std::process;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use sysinfo::{System, SystemExt};
+use nonvolatile::State;
+
+
+fn storage_dir() -> String {
+	let dir = std::env::temp_dir().join(format!("nonvolatile_bench_{}", process::id()));
+	dir.to_string_lossy().to_string()
+}
+
+
+fn bench_liveness(c: &mut Criterion) {
+	let dir = storage_dir();
+	let name = "liveness";
+	let lockfile_path = format!("{}/{}/~rust_nonvolatile.lock", dir, name);
+	drop(State::new_from(name, &dir).unwrap());
+
+	c.bench_function("sysinfo_scan", |b| b.iter(|| {
+		let mut system = System::new();
+		system.refresh_processes();
+		system
+	}));
+
+	//the lockfile names a process that doesn't exist, so loading takes over the lock
+	let dead_owner = "4000000000\n1\n/nonexistent/exe\n";
+	c.bench_function("load_with_dead_owner", |b| b.iter_batched(
+		|| write(&lockfile_path, dead_owner).unwrap(),
+		|_| drop(State::load_from(name, &dir).unwrap()),
+		BatchSize::SmallInput,
+	));
+
+	//the lockfile names this (live) process, so loading fails
+	let live_owner = format!("{}\n1\n{}\n", process::id(), std::env::current_exe().unwrap().to_string_lossy());
+	write(&lockfile_path, &live_owner).unwrap();
+	c.bench_function("load_with_live_owner", |b| b.iter(|| {
+		assert!(State::load_from(name, &dir).is_err());
+	}));
+
+	let _ = remove_dir_all(&dir);
+}
+
+
+criterion_group!(benches, bench_liveness);
+criterion_main!(benches);
@@ -0,0 +1,189 @@
+/*
+async API for nonvolatile states, for use with tokio
+*/
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use generic_error::{Result, GenErr, GenericError};
+
+use crate::{State, StateBuilder};
+
+
+///An open `State` that can be used from async code without blocking the runtime.
+///
+///Opening a state (which looks up the current process and takes the lock) and writing the
+///manifest run on tokio's blocking thread pool, and waiting for a lock held by someone else
+///is done with async sleeps. `get` and `has` are plain functions: `has` never does I/O, and
+///`get` only reads what `State::get` would (a blob file, or an item of a lazily loaded
+///manifest). Both wait for a write that's still running. Requires the `tokio` feature.
+///
+///If the future of a `set`, `delete` or `flush` is dropped before it completes, the write
+///still finishes in the background, and the state stays usable.
+///
+///### Example
+///
+///```rust
+///let mut state = AsyncState::load_else_create("my_service").await?;
+///state.set("last_startup", now).await?;
+///let retries: Option<u32> = state.get("retries");
+///```
+#[derive(Debug)]
+pub struct AsyncState {
+	//shared with the blocking task doing a write, so that nothing is lost if the future
+	//waiting on it is dropped
+	state: Arc<Mutex<State>>,
+	//whether the last write failed, so that `flush` has something to retry
+	dirty: Arc<AtomicBool>,
+}
+
+
+fn lock_state(state: &Mutex<State>) -> MutexGuard<'_, State> {
+	match state.lock() {
+		Ok(state) => state,
+		Err(poisoned) => poisoned.into_inner(),
+	}
+}
+
+
+impl AsyncState {
+
+	///Open a state with the given options. See `State::builder`.
+	///
+	///If the state is locked by someone else, this keeps retrying (sleeping asynchronously
+	///in between) for up to the builder's `lock_timeout`.
+	pub async fn open(builder: StateBuilder) -> Result<AsyncState> {
+		let timeout = builder.get_lock_timeout();
+		let start = Instant::now();
+		loop {
+			let attempt = builder.clone().lock_timeout(Duration::from_secs(0));
+			let result = spawn_blocking(move || {
+				let probe = attempt.clone();
+				let result = attempt.open();
+				let locked = result.is_err() && probe.lock_held_by_other();
+				(result, locked)
+			}).await;
+			let (result, locked) = match result {
+				Ok(result) => result,
+				Err(e) => return GenErr!("nonvolatile: failed to open state: {}", e),
+			};
+			match result {
+				Ok(state) => return Ok(AsyncState::from(state)),
+				Err(e) => {
+					let elapsed = start.elapsed();
+					if !locked || elapsed >= timeout {
+						return Err(e);
+					}
+					sleep((timeout - elapsed).min(Duration::from_millis(50))).await;
+				},
+			}
+		}
+	}
+
+
+	///Create a new state with the given name. See `State::new`.
+	pub async fn new(name: &str) -> Result<AsyncState> {
+		AsyncState::open(State::builder(name).overwrite(true)).await
+	}
+
+
+	///Load the state with the given name. See `State::load`.
+	pub async fn load(name: &str) -> Result<AsyncState> {
+		AsyncState::open(State::builder(name)).await
+	}
+
+
+	///Load the state with the given name, or create it if it doesn't exist. See `State::load_else_create`.
+	pub async fn load_else_create(name: &str) -> Result<AsyncState> {
+		AsyncState::open(State::builder(name).create_if_missing(true)).await
+	}
+
+
+	///Run `write` on the state on tokio's blocking thread pool, then wait for it to reach the
+	///disk. If that fails, the next `flush` tries again.
+	async fn write<F>(&self, write: F) -> Result<()> where F: FnOnce(&mut State) -> Result<()> + Send + 'static {
+		lock_state(&self.state).check_writable()?;
+		let (state, dirty) = (self.state.clone(), self.dirty.clone());
+		let result = spawn_blocking(move || {
+			let mut state = lock_state(&state);
+			let result = write(&mut state).and_then(|_| state.backend.sync(&state.name));
+			dirty.store(result.is_err(), Ordering::SeqCst);
+			result
+		}).await;
+		match result {
+			Ok(result) => result,
+			Err(e) => {
+				self.dirty.store(true, Ordering::SeqCst);
+				GenErr!("nonvolatile: failed to write state: {}", e)
+			},
+		}
+	}
+
+
+	///Set a variable with name `var` and value `value`, and write it to storage. See `State::set`.
+	pub async fn set<T>(&mut self, var: &str, value: T) -> Result<()> where T: Serialize {
+		let (var, value) = (String::from(var), serde_yaml::to_string(&value)?);
+		self.write(move |state| state.set_serialized(&var, value)).await
+	}
+
+
+	///Try to retrieve a variable. See `State::get`.
+	pub fn get<T>(&self, var: &str) -> Option<T> where for<'a> T: Deserialize<'a> {
+		lock_state(&self.state).get(var)
+	}
+
+
+	///Check if the given item/key exists in the state. See `State::has`.
+	pub fn has(&self, item: &str) -> bool {
+		lock_state(&self.state).has(item)
+	}
+
+
+	///Delete a stored variable, and write the change to storage. See `State::delete`.
+	pub async fn delete(&mut self, name: &str) -> Result<()> {
+		let name = String::from(name);
+		self.write(move |state| state.delete(&name)).await
+	}
+
+
+	///Write out any changes that haven't made it to storage yet (because an earlier write
+	///failed), and wait for them to reach the disk.
+	pub async fn flush(&mut self) -> Result<()> {
+		if !self.dirty.load(Ordering::SeqCst) {
+			return Ok(());
+		}
+		self.write(|state| state.commit(None, |_| ())).await
+	}
+
+
+	///Get the underlying (blocking) `State` back. If a write whose future was dropped is 
+	///still running, this waits for it to finish.
+	pub fn into_state(self) -> State {
+		let mut state = self.state;
+		loop {
+			match Arc::try_unwrap(state) {
+				Ok(state) => return match state.into_inner() {
+					Ok(state) => state,
+					Err(poisoned) => poisoned.into_inner(),
+				},
+				Err(shared) => state = shared,
+			}
+			thread::sleep(Duration::from_millis(1));
+		}
+	}
+}
+
+
+impl From<State> for AsyncState {
+	fn from(state: State) -> AsyncState {
+		AsyncState {
+			state: Arc::new(Mutex::new(state)),
+			dirty: Arc::new(AtomicBool::new(false)),
+		}
+	}
+}
+
@@ -0,0 +1,482 @@
+/*
+storage backends for nonvolatile states
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{metadata, read, read_dir, remove_dir_all, remove_file, rename, read_to_string, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::sync::{Mutex, MutexGuard};
+use generic_error::{Result, GenErr, GenericError};
+
+use crate::{blobs, lock, permissions, acquire_dir, register_shared, canonicalize_path, LOCKFILE_NAME, SHARED_LOCKFILE_PREFIX};
+
+
+//the items of a state using the write-ahead log, as of the last compaction
+const SNAPSHOT_NAME: &str = ".snapshot";
+//every change since the last compaction, one per line
+const LOG_NAME: &str = ".log";
+//compact once the log is this many times the size of the snapshot...
+const LOG_COMPACTION_RATIO: u64 = 2;
+//...and at least this big
+const LOG_COMPACTION_MIN: u64 = 64 * 1024;
+
+
+///Where states are stored, and how they're locked.
+///
+///`State` only talks to storage through a `Backend`, so states can be kept somewhere other
+///than the filesystem by implementing this trait and passing it to `StateBuilder::backend`.
+///One backend holds any number of states, told apart by name.
+///
+///Lock owners are identified by opaque strings. Whoever holds a lock should keep it until
+///`release_lock` is called with the same owner, or until the backend can tell that the
+///owner is gone.
+///
+///### Example
+///
+///```rust
+///let backend: Arc<dyn Backend> = Arc::new(MemoryBackend::new());
+///let state = State::builder("my_state")
+///    .backend(backend.clone())
+///    .create_if_missing(true)
+///    .open()?;
+///```
+pub trait Backend: Send + Sync + fmt::Debug {
+	///Read the manifest of the state `name`, or `None` if there's no such state.
+	fn read_manifest(&self, name: &str) -> Result<Option<Vec<u8>>>;
+
+	///Replace the manifest of the state `name` with `data`, creating the state if needed. This
+	///must be atomic: anyone reading the manifest sees either the old one or the new one.
+	fn write_manifest(&self, name: &str, data: &[u8]) -> Result<()>;
+
+	///Whether there's a state called `name`.
+	fn exists(&self, name: &str) -> Result<bool> {
+		Ok(self.read_manifest(name)?.is_some())
+	}
+
+	///Wait until everything written to the state `name` has reached durable storage.
+	fn sync(&self, _name: &str) -> Result<()> {
+		Ok(())
+	}
+
+	///Whether this backend stores each item of a state separately from its manifest, so that
+	///setting one item doesn't mean rewriting all of them. If so, manifests are written without
+	///their items, and `read_items`, `write_items` and `replace_items` are used for those
+	///instead (except for encrypted states, which are always stored as a whole).
+	fn stores_items(&self) -> bool {
+		false
+	}
+
+	///Read all the items of the state `name`. Only used if `stores_items` returns true.
+	fn read_items(&self, _name: &str) -> Result<HashMap<String, String>> {
+		GenErr!("nonvolatile: this backend doesn't store items separately")
+	}
+
+	///Set (or for `None`, delete) some items of the state `name`. Only used if `stores_items`
+	///returns true.
+	fn write_items(&self, _name: &str, _changes: &[(&str, Option<&str>)]) -> Result<()> {
+		GenErr!("nonvolatile: this backend doesn't store items separately")
+	}
+
+	///Replace all the items of the state `name`. Only used if `stores_items` returns true.
+	fn replace_items(&self, _name: &str, _items: &HashMap<String, String>) -> Result<()> {
+		GenErr!("nonvolatile: this backend doesn't store items separately")
+	}
+
+	///Lock the state `name` for `owner`. Fails if someone else holds the lock, and succeeds
+	///if `owner` already does.
+	fn acquire_lock(&self, name: &str, owner: &str) -> Result<()>;
+
+	///Release the lock on the state `name`, if `owner` holds it.
+	fn release_lock(&self, name: &str, owner: &str) -> Result<()>;
+
+	///The names of all the states in this backend.
+	fn list(&self) -> Result<Vec<String>>;
+
+	///Delete the state `name`. Nothing happens if there's no such state.
+	fn destroy(&self, name: &str) -> Result<()>;
+}
+
+
+///Stores each state in its own directory, `<dir>/<name>`, holding the manifest and lockfiles.
+///This is the backend used unless another one is chosen.
+///
+///Normally the whole state is kept in the manifest. With the write-ahead log (see 
+///`StateBuilder::write_ahead_log`), items are kept in a snapshot instead, and each `set` or 
+///`delete` just appends a record to a log. Loading replays the log over the snapshot, and 
+///once the log grows to twice the size of the snapshot, the two are compacted into a new 
+///snapshot.
+#[derive(Clone, Debug)]
+pub struct FsBackend {
+	dir: String,
+	shared: bool,
+	log: bool,
+	read_only: bool,
+}
+
+
+impl FsBackend {
+
+	///Store states in the directory `dir`.
+	pub fn new(dir: &str) -> FsBackend {
+		FsBackend {
+			dir: String::from(dir),
+			shared: false,
+			log: false,
+			read_only: false,
+		}
+	}
+
+
+	///Lock states in shared mode. See `StateBuilder::shared`.
+	pub(crate) fn shared(mut self, shared: bool) -> FsBackend {
+		self.shared = shared;
+		self
+	}
+
+
+	///Only read states. Their directories may then belong to another user, e.g. root for a
+	///system-wide state, since nothing is written to them.
+	pub(crate) fn read_only(mut self, read_only: bool) -> FsBackend {
+		self.read_only = read_only;
+		self
+	}
+
+
+	///Store items in a snapshot and a write-ahead log, instead of in the manifest.
+	pub(crate) fn log(mut self, log: bool) -> FsBackend {
+		self.log = log;
+		self
+	}
+
+
+	///Whether the state `name` is stored with a write-ahead log.
+	pub(crate) fn uses_log(&self, name: &str) -> bool {
+		let path = self.state_path(name);
+		metadata(format!("{}/{}", path, SNAPSHOT_NAME)).is_ok() || metadata(format!("{}/{}", path, LOG_NAME)).is_ok()
+	}
+
+
+	///Remove the snapshot and log of the state `name`, if it has them.
+	pub(crate) fn remove_log(&self, name: &str) -> Result<()> {
+		let path = self.state_path(name);
+		for file in &[SNAPSHOT_NAME, LOG_NAME] {
+			match remove_file(format!("{}/{}", path, file)) {
+				Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+				_ => (),
+			}
+		}
+		Ok(())
+	}
+
+
+	///Cut off a record at the end of the log of the state `name` that a crash left half-written,
+	///so that the next record isn't appended to it. Reading the log skips such a record, but 
+	///one that's followed by another would make the log unreadable.
+	fn repair_log(&self, name: &str) -> Result<()> {
+		let mut file = match OpenOptions::new().read(true).write(true).open(format!("{}/{}", self.state_path(name), LOG_NAME)) {
+			Ok(file) => file,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(e.into()),
+		};
+		if file.metadata()?.len() == 0 {
+			return Ok(());
+		}
+		let mut last = [0u8];
+		let _ = file.seek(SeekFrom::End(-1))?;
+		file.read_exact(&mut last)?;
+		if last[0] == b'\n' {
+			return Ok(());
+		}
+		let mut log = Vec::new();
+		let _ = file.seek(SeekFrom::Start(0))?;
+		let _ = file.read_to_end(&mut log)?;
+		let complete = log.iter().rposition(|b| *b == b'\n').map(|i| i + 1).unwrap_or(0);
+		file.set_len(complete as u64)?;
+		Ok(())
+	}
+
+
+	///Compact the log into a new snapshot if it's grown too big.
+	fn maybe_compact(&self, name: &str) -> Result<()> {
+		let path = self.state_path(name);
+		let size = |file: &str| metadata(format!("{}/{}", path, file)).map(|m| m.len()).unwrap_or(0);
+		let log_size = size(LOG_NAME);
+		if log_size < LOG_COMPACTION_MIN || log_size < size(SNAPSHOT_NAME) * LOG_COMPACTION_RATIO {
+			return Ok(());
+		}
+		let items = self.read_items(name)?;
+		self.replace_items(name, &items)
+	}
+
+
+	///The directory this backend stores states in.
+	pub fn dir(&self) -> &str {
+		&self.dir
+	}
+
+
+	pub(crate) fn state_path(&self, name: &str) -> String {
+		canonicalize_path(format!("{}/{}", self.dir, name))
+	}
+
+
+	///The lockfile that `owner` holds while it has the state `name` open.
+	pub(crate) fn lockfile_path(&self, name: &str, owner: &str) -> String {
+		let path = self.state_path(name);
+		match self.shared {
+			true => {
+				let id = lock::LockInfo::parse(owner).and_then(|info| info.random).unwrap_or_default();
+				format!("{}/{}{:08x}", path, SHARED_LOCKFILE_PREFIX, id)
+			},
+			false => format!("{}/{}", path, LOCKFILE_NAME),
+		}
+	}
+
+
+	///Make sure the directory of the state `name` is safe to use, if it exists.
+	fn check_dir(&self, name: &str) -> Result<String> {
+		let path = self.state_path(name);
+		if metadata(&path).is_ok() {
+			permissions::check_dir(&path, !self.read_only)?;
+		}
+		Ok(path)
+	}
+}
+
+
+impl Backend for FsBackend {
+
+	fn read_manifest(&self, name: &str) -> Result<Option<Vec<u8>>> {
+		let path = self.check_dir(name)?;
+		match read(format!("{}/{}", path, ".manifest")) {
+			Ok(data) => Ok(Some(data)),
+			Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+
+	fn write_manifest(&self, name: &str, data: &[u8]) -> Result<()> {
+		let path = self.state_path(name);
+		let tmp_manifest_path = format!("{}/{}", path, ".manifest_tmp");
+		let mut file = permissions::create_file(&tmp_manifest_path)?;
+		file.write_all(data)?;
+		rename(&tmp_manifest_path, format!("{}/{}", path, ".manifest"))?;
+		Ok(())
+	}
+
+
+	fn exists(&self, name: &str) -> Result<bool> {
+		Ok(metadata(format!("{}/{}", self.state_path(name), ".manifest")).is_ok())
+	}
+
+
+	fn stores_items(&self) -> bool {
+		self.log
+	}
+
+
+	fn read_items(&self, name: &str) -> Result<HashMap<String, String>> {
+		let path = self.state_path(name);
+		let mut items: HashMap<String, String> = match read(format!("{}/{}", path, SNAPSHOT_NAME)) {
+			Ok(data) => serde_json::from_slice(&data)?,
+			Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+			Err(e) => return Err(e.into()),
+		};
+		let log = match read_to_string(format!("{}/{}", path, LOG_NAME)) {
+			Ok(log) => log,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(items),
+			Err(e) => return Err(e.into()),
+		};
+		let lines: Vec<&str> = log.lines().collect();
+		for (i, line) in lines.iter().enumerate() {
+			match serde_json::from_str::<(String, Option<String>)>(line) {
+				Ok((key, Some(value))) => {
+					let _ = items.insert(key, value);
+				},
+				Ok((key, None)) => {
+					let _ = items.remove(&key);
+				},
+				//the last record may have been cut off by a crash, in which case it never happened
+				Err(_) if i == lines.len() - 1 => (),
+				Err(e) => return GenErr!("nonvolatile: log of state \"{}\" is corrupt at line {}: {}", name, i + 1, e),
+			}
+		}
+		Ok(items)
+	}
+
+
+	fn write_items(&self, name: &str, changes: &[(&str, Option<&str>)]) -> Result<()> {
+		let mut records = String::new();
+		for change in changes {
+			records.push_str(&serde_json::to_string(change)?);
+			records.push('\n');
+		}
+		self.repair_log(name)?;
+		let mut file = permissions::append_file(&format!("{}/{}", self.state_path(name), LOG_NAME))?;
+		file.write_all(records.as_bytes())?;
+		drop(file);
+		self.maybe_compact(name)
+	}
+
+
+	fn replace_items(&self, name: &str, items: &HashMap<String, String>) -> Result<()> {
+		let path = self.state_path(name);
+		let tmp_snapshot_path = format!("{}/{}_tmp", path, SNAPSHOT_NAME);
+		let mut file = permissions::create_file(&tmp_snapshot_path)?;
+		file.write_all(&serde_json::to_vec(items)?)?;
+		drop(file);
+		rename(&tmp_snapshot_path, format!("{}/{}", path, SNAPSHOT_NAME))?;
+		//everything in the log is in the new snapshot. If we crash before the log is gone, 
+		//replaying it over the new snapshot doesn't change anything.
+		match remove_file(format!("{}/{}", path, LOG_NAME)) {
+			Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+			_ => Ok(()),
+		}
+	}
+
+
+	fn sync(&self, name: &str) -> Result<()> {
+		let path = self.state_path(name);
+		File::open(format!("{}/{}", path, ".manifest"))?.sync_all()?;
+		for file in &[SNAPSHOT_NAME, LOG_NAME] {
+			match File::open(format!("{}/{}", path, file)) {
+				Ok(file) => file.sync_all()?,
+				Err(e) if e.kind() == ErrorKind::NotFound => (),
+				Err(e) => return Err(e.into()),
+			}
+		}
+		blobs::sync_blobs(&path)?;
+		//make sure the rename that put the manifest in place is durable too
+		#[cfg(unix)]
+		File::open(&path)?.sync_all()?;
+		Ok(())
+	}
+
+
+	fn acquire_lock(&self, name: &str, owner: &str) -> Result<()> {
+		let path = self.check_dir(name)?;
+		permissions::create_dir(&path)?;
+		permissions::restrict(&path)?;
+		match self.shared {
+			true => register_shared(&path, &self.lockfile_path(name, owner), owner),
+			false => acquire_dir(&self.lockfile_path(name, owner), owner),
+		}
+	}
+
+
+	fn release_lock(&self, name: &str, owner: &str) -> Result<()> {
+		let lockfile_path = self.lockfile_path(name, owner);
+		match read_to_string(&lockfile_path) {
+			//if the lock was taken over with `force_unlock`, it isn't ours to remove anymore
+			Ok(read_id) if !lock::same_owner(&read_id, owner) => Ok(()),
+			Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+			_ => Ok(remove_file(&lockfile_path)?),
+		}
+	}
+
+
+	fn list(&self) -> Result<Vec<String>> {
+		let entries = match read_dir(&self.dir) {
+			Ok(entries) => entries,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(e) => return Err(e.into()),
+		};
+		let mut names = Vec::new();
+		for entry in entries.flatten() {
+			let name = entry.file_name().to_string_lossy().to_string();
+			//state names can't start with a dot, so these are someone's temporary directories
+			if !name.starts_with('.') && metadata(entry.path().join(".manifest")).is_ok() {
+				names.push(name);
+			}
+		}
+		names.sort();
+		Ok(names)
+	}
+
+
+	fn destroy(&self, name: &str) -> Result<()> {
+		match remove_dir_all(self.state_path(name)) {
+			Ok(()) => Ok(()),
+			Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+			Err(e) => Err(e.into()),
+		}
+	}
+}
+
+
+///Keeps states in memory, so nothing is ever written to disk. States last as long as the
+///backend does; share it between states (with an `Arc`) to reopen them. Mostly useful for
+///tests. See also `State::in_memory`.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+	manifests: Mutex<HashMap<String, Vec<u8>>>,
+	locks: Mutex<HashMap<String, String>>,
+}
+
+
+fn lock_map<T>(map: &Mutex<T>) -> MutexGuard<'_, T> {
+	match map.lock() {
+		Ok(map) => map,
+		Err(poisoned) => poisoned.into_inner(),
+	}
+}
+
+
+impl MemoryBackend {
+
+	pub fn new() -> MemoryBackend {
+		MemoryBackend::default()
+	}
+}
+
+
+impl Backend for MemoryBackend {
+
+	fn read_manifest(&self, name: &str) -> Result<Option<Vec<u8>>> {
+		Ok(lock_map(&self.manifests).get(name).cloned())
+	}
+
+
+	fn write_manifest(&self, name: &str, data: &[u8]) -> Result<()> {
+		let _ = lock_map(&self.manifests).insert(String::from(name), data.to_vec());
+		Ok(())
+	}
+
+
+	fn acquire_lock(&self, name: &str, owner: &str) -> Result<()> {
+		let mut locks = lock_map(&self.locks);
+		match locks.get(name) {
+			Some(holder) if !lock::same_owner(holder, owner) => {
+				GenErr!("nonvolatile: state \"{}\" is already in use", name)
+			},
+			_ => {
+				let _ = locks.insert(String::from(name), String::from(owner));
+				Ok(())
+			},
+		}
+	}
+
+
+	fn release_lock(&self, name: &str, owner: &str) -> Result<()> {
+		let mut locks = lock_map(&self.locks);
+		if locks.get(name).map(|holder| lock::same_owner(holder, owner)) == Some(true) {
+			let _ = locks.remove(name);
+		}
+		Ok(())
+	}
+
+
+	fn list(&self) -> Result<Vec<String>> {
+		let mut names: Vec<String> = lock_map(&self.manifests).keys().cloned().collect();
+		names.sort();
+		Ok(names)
+	}
+
+
+	fn destroy(&self, name: &str) -> Result<()> {
+		let _ = lock_map(&self.manifests).remove(name);
+		Ok(())
+	}
+}
@@ -0,0 +1,109 @@
+/*
+storing large values in their own files, named after their contents
+*/
+
+use std::collections::HashSet;
+use std::fs::{metadata, read_dir, read_to_string, remove_file, rename, File};
+use std::io::{ErrorKind, Write};
+use sha2::{Digest, Sha256};
+use generic_error::Result;
+
+use crate::permissions;
+
+
+const BLOB_DIR_NAME: &str = ".blobs";
+const BLOB_TMP_PREFIX: &str = "~tmp.";
+
+
+fn blob_dir(state_path: &str) -> String {
+	format!("{}/{}", state_path, BLOB_DIR_NAME)
+}
+
+
+///The name a value is stored under: the hex SHA-256 of its contents.
+pub(crate) fn digest(value: &str) -> String {
+	Sha256::digest(value.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+///Whether `digest` could have come from `digest`, and so is safe to use as a file name.
+pub(crate) fn is_digest(digest: &str) -> bool {
+	digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+
+///Write `value` to the blob directory of the state at `state_path`, and return its digest.
+///Values that are already stored aren't written again.
+pub(crate) fn write_blob(state_path: &str, value: &str) -> Result<String> {
+	let digest = digest(value);
+	let dir = blob_dir(state_path);
+	let path = format!("{}/{}", dir, digest);
+	if metadata(&path).is_ok() {
+		return Ok(digest);
+	}
+	permissions::create_dir(&dir)?;
+	let tmp_path = format!("{}/{}{:08x}", dir, BLOB_TMP_PREFIX, rand::random::<u32>());
+	let result = permissions::create_file(&tmp_path)
+		.and_then(|mut file| Ok(file.write_all(value.as_bytes())?))
+		.and_then(|_| Ok(rename(&tmp_path, &path)?));
+	if result.is_err() {
+		let _ = remove_file(&tmp_path);
+	}
+	result.map(|_| digest)
+}
+
+
+///Read the blob with the given digest.
+pub(crate) fn read_blob(state_path: &str, digest: &str) -> Result<String> {
+	Ok(read_to_string(format!("{}/{}", blob_dir(state_path), digest))?)
+}
+
+
+///Remove the blob with the given digest, if it's there.
+pub(crate) fn remove_blob(state_path: &str, digest: &str) -> Result<()> {
+	match remove_file(format!("{}/{}", blob_dir(state_path), digest)) {
+		Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+		_ => Ok(()),
+	}
+}
+
+
+///Make sure every blob of the state at `state_path`, and the directory entries for them,
+///have reached the disk.
+pub(crate) fn sync_blobs(state_path: &str) -> Result<()> {
+	let dir = blob_dir(state_path);
+	let entries = match read_dir(&dir) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(e.into()),
+	};
+	for entry in entries {
+		//another process may have renamed its temporary file in the meantime
+		match File::open(entry?.path()) {
+			Ok(file) => file.sync_all()?,
+			Err(e) if e.kind() == ErrorKind::NotFound => (),
+			Err(e) => return Err(e.into()),
+		}
+	}
+	#[cfg(unix)]
+	File::open(&dir)?.sync_all()?;
+	Ok(())
+}
+
+
+///Remove every blob (and leftover temporary file) that isn't in `referenced`.
+pub(crate) fn collect_garbage<'a, I>(state_path: &str, referenced: I) -> Result<()> where I: IntoIterator<Item = &'a String> {
+	let referenced: HashSet<&String> = referenced.into_iter().collect();
+	let entries = match read_dir(blob_dir(state_path)) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(e.into()),
+	};
+	for entry in entries {
+		let name = entry?.file_name().to_string_lossy().to_string();
+		if !referenced.contains(&name) {
+			remove_blob(state_path, &name)?;
+		}
+	}
+	Ok(())
+}
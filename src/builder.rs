@@ -0,0 +1,632 @@
+/*
+builder for opening nonvolatile states with options
+*/
+
+use std::fs::remove_file;
+#[cfg(feature = "mmap")]
+use std::fs::File;
+#[cfg(feature = "mmap")]
+use std::io::{ErrorKind, Read};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use generic_error::{Result, GenErr, GenericError};
+
+use crate::*;
+
+
+///The format a state's manifest is written in. Any of them can be loaded regardless of this
+///setting; it only affects how the manifest is written.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Format {
+	#[default]
+	Yaml,
+	Json,
+	///A plain YAML document with one entry per item, meant to be edited by hand. Comments, 
+	///blank lines and the order of entries are kept when the state is written, and entries 
+	///are only rewritten when their value changes. Use `State::reload` to pick up changes 
+	///made while the state is open.
+	///
+	///A state whose manifest is in this format keeps it unless another format is chosen 
+	///explicitly. Items are always kept in the manifest, even with `write_ahead_log` or a 
+	///backend that stores items separately.
+	Editable,
+}
+
+
+///Options for opening a `State`, created with `State::builder`.
+///
+///By default, `open` loads an existing state from the default storage directory, failing if
+///it doesn't exist or is in use by someone else.
+///
+///### Example
+///
+///```rust
+///let state = State::builder("my_state")
+///    .storage_dir("/srv/my_app")
+///    .create_if_missing(true)
+///    .format(Format::Json)
+///    .lock_timeout(Duration::from_secs(5))
+///    .open()?;
+///```
+#[derive(Clone, Debug)]
+pub struct StateBuilder {
+	name: String,
+	storage_dir: Option<String>,
+	kind: StateKind,
+	create_if_missing: bool,
+	overwrite: bool,
+	format: Option<Format>,
+	lock_timeout: Duration,
+	read_only: bool,
+	shared: bool,
+	lease: Option<Duration>,
+	write_ahead_log: bool,
+	blob_threshold: Option<usize>,
+	lazy: bool,
+	#[cfg(feature = "mmap")]
+	mmap: bool,
+	backend: Option<Arc<dyn Backend>>,
+	migrate_from: Option<String>,
+	#[cfg(feature = "encryption")]
+	key: Option<EncryptionKey>,
+}
+
+
+impl StateBuilder {
+
+	pub(crate) fn new(name: &str) -> StateBuilder {
+		StateBuilder {
+			name: String::from(name),
+			storage_dir: None,
+			kind: StateKind::Config,
+			create_if_missing: false,
+			overwrite: false,
+			format: None,
+			lock_timeout: Duration::from_secs(0),
+			read_only: false,
+			shared: false,
+			lease: None,
+			write_ahead_log: false,
+			blob_threshold: None,
+			lazy: false,
+			#[cfg(feature = "mmap")]
+			mmap: false,
+			backend: None,
+			migrate_from: None,
+			#[cfg(feature = "encryption")]
+			key: None,
+		}
+	}
+
+
+	///Use a custom storage location instead of the default one. The state will be
+	///stored in `<storage_path>/<name>`. See `State::new_from`.
+	pub fn storage_dir(mut self, storage_path: &str) -> StateBuilder {
+		self.storage_dir = Some(String::from(storage_path));
+		self
+	}
+
+
+	///Which default storage directory to use (see `nonvolatile::storage_dir`). Has no effect
+	///if `storage_dir` is set.
+	pub fn kind(mut self, kind: StateKind) -> StateBuilder {
+		self.kind = kind;
+		self
+	}
+
+
+	///Create a new state if there isn't one already, instead of failing.
+	pub fn create_if_missing(mut self, create_if_missing: bool) -> StateBuilder {
+		self.create_if_missing = create_if_missing;
+		self
+	}
+
+
+	///Always create a new, empty state, replacing any existing state of the same name.
+	pub fn overwrite(mut self, overwrite: bool) -> StateBuilder {
+		self.overwrite = overwrite;
+		self
+	}
+
+
+	///The format the manifest is written in. Defaults to `Format::Yaml`.
+	pub fn format(mut self, format: Format) -> StateBuilder {
+		self.format = Some(format);
+		self
+	}
+
+
+	///If the state is in use by someone else, keep trying for up to `timeout` before giving up.
+	///Defaults to zero (give up immediately).
+	pub fn lock_timeout(mut self, timeout: Duration) -> StateBuilder {
+		self.lock_timeout = timeout;
+		self
+	}
+
+
+	///Open the state without taking the lock, so it can be read while someone else has it
+	///open. `set` and `delete` will return errors. A read-only state can't be created.
+	///
+	///Since nothing is written to it, the state's directory may belong to another user (e.g. 
+	///a system-wide state owned by root), though it still mustn't be world-writable.
+	pub fn read_only(mut self, read_only: bool) -> StateBuilder {
+		self.read_only = read_only;
+		self
+	}
+
+
+	///Open the state in shared mode, so that several processes can have it open (and write 
+	///to it) at once. 
+	///
+	///Each `set` or `delete` briefly takes a write lock, re-reads the manifest, applies just 
+	///that change, and writes it back, so processes writing different keys never lose each 
+	///other's updates. Use `State::reload` to pick up changes made by others. A state that's 
+	///open in shared mode can't be opened exclusively, and vice versa.
+	pub fn shared(mut self, shared: bool) -> StateBuilder {
+		self.shared = shared;
+		self
+	}
+
+
+	///Hold the lock on a lease, so that it can be taken over if this process stops renewing it.
+	///
+	///Normally a lock is held for as long as the owning process is alive, which can't be 
+	///checked if the owner is on another host. With a lease, a background thread rewrites the 
+	///lockfile every `lease / 3` with a new expiry time, and anyone opening the state once 
+	///`lease` has passed without a renewal takes over the lock, even if the owner is still 
+	///running. The hosts' clocks need to agree to within a fraction of `lease`.
+	///
+	///Once the lease has run out or the lock has been taken over, `set` and `delete` return 
+	///errors, even if the lease is renewed later.
+	pub fn lease(mut self, lease: Duration) -> StateBuilder {
+		self.lease = Some(lease);
+		self
+	}
+
+
+	///Write each change to an append-only log instead of rewriting the whole manifest, so that
+	///`set` and `delete` stay cheap for states with a lot of items. The log is replayed over
+	///the last snapshot of the items when the state is loaded, and compacted into a new
+	///snapshot whenever it gets twice as big as the snapshot.
+	///
+	///Once a state uses the log, it keeps using it, even when loaded without this option.
+	///Has no effect on encrypted states or states stored in another backend.
+	pub fn write_ahead_log(mut self, write_ahead_log: bool) -> StateBuilder {
+		self.write_ahead_log = write_ahead_log;
+		self
+	}
+
+
+	///Store values whose serialized form is bigger than `bytes` in files of their own in the 
+	///state's directory, instead of in the manifest, so that big values don't have to be 
+	///rewritten every time something else changes. The files are named after a hash of their 
+	///contents, so identical values are only stored once, and are removed once nothing refers 
+	///to them anymore. 
+	///
+	///Has no effect on encrypted states, shared mode, or states stored in another backend.
+	pub fn blob_threshold(mut self, bytes: usize) -> StateBuilder {
+		self.blob_threshold = Some(bytes);
+		self
+	}
+
+
+	///Only index the items when the state is loaded, and decode each one the first time it's 
+	///used, so that opening a big state is quick. The first change to the state decodes all of 
+	///the remaining items, so this is mostly useful for states that are read much more often 
+	///than they're written.
+	///
+	///Only JSON manifests (see `Format::Json`) can be indexed; YAML ones are parsed up front as 
+	///usual. Has no effect on encrypted states or on states whose items are stored separately 
+	///from the manifest (see `write_ahead_log`).
+	pub fn lazy(mut self, lazy: bool) -> StateBuilder {
+		self.lazy = lazy;
+		self
+	}
+
+
+	///Map the manifest into memory instead of reading it, and load the state lazily (see 
+	///`lazy`), so that items that are never used are never read from disk at all. Only works 
+	///for states stored in the filesystem. Requires the `mmap` feature.
+	///
+	///The manifest must not be changed in place while the state is open; nonvolatile itself
+	///only ever replaces it. Editable manifests (see `Format::Editable`), which are meant to
+	///be edited by hand, are read as usual instead, and asking for both is an error.
+	#[cfg(feature = "mmap")]
+	pub fn mmap(mut self, mmap: bool) -> StateBuilder {
+		self.mmap = mmap;
+		self
+	}
+
+
+	///Store the state in `backend` instead of the filesystem. `storage_dir` and `kind` have no
+	///effect, and shared mode and leases can't be used. See `Backend`.
+	pub fn backend(mut self, backend: Arc<dyn Backend>) -> StateBuilder {
+		self.backend = Some(backend);
+		self
+	}
+
+
+	///If the state doesn't exist in the chosen backend yet, but there is a state with the same
+	///name in the usual directory layout under `storage_path`, move it into the backend. Use
+	///this when switching an existing state over to another backend, e.g. `SqliteBackend`.
+	///
+	///### Example
+	///
+	///```rust
+	///let state = State::builder("my_state")
+	///    .backend(Arc::new(SqliteBackend::open("states.db")?))
+	///    .migrate_from(&nonvolatile::storage_dir(StateKind::Config)?)
+	///    .create_if_missing(true)
+	///    .open()?;
+	///```
+	pub fn migrate_from(mut self, storage_path: &str) -> StateBuilder {
+		self.migrate_from = Some(String::from(storage_path));
+		self
+	}
+
+
+	///Encrypt the manifest with the given key. See `State::load_encrypted`.
+	#[cfg(feature = "encryption")]
+	pub fn encryption_key(mut self, key: EncryptionKey) -> StateBuilder {
+		self.key = Some(key);
+		self
+	}
+
+
+	///Open the state with the chosen options.
+	pub fn open(self) -> Result<State> {
+		check_path_valid(&self.name)?;
+		#[cfg(feature = "mmap")]
+		{
+			if self.mmap && self.format == Some(Format::Editable) {
+				return GenErr!("nonvolatile: editable manifests can be edited in place, so they can't be memory-mapped");
+			}
+		}
+		let (backend, fs): (Arc<dyn Backend>, Option<FsBackend>) = match &self.backend {
+			Some(_) if self.shared || self.lease.is_some() => {
+				return GenErr!("nonvolatile: shared mode and leases are only supported for states stored in the filesystem");
+			},
+			Some(backend) => (backend.clone(), None),
+			None => {
+				let fs = FsBackend::new(&self.fs_dir()?).shared(self.shared).read_only(self.read_only);
+				let log = self.write_ahead_log || (!self.overwrite && fs.uses_log(&self.name));
+				let fs = fs.log(log);
+				(Arc::new(fs.clone()), Some(fs))
+			},
+		};
+		let exists = backend.exists(&self.name)?;
+
+		if let (Some(dir), false, false, false) = (&self.migrate_from, exists, self.overwrite, self.read_only) {
+			if FsBackend::new(dir).exists(&self.name)? {
+				let dir = dir.clone();
+				return self.migrate(&dir);
+			}
+		}
+
+		if self.overwrite || (self.create_if_missing && !exists) {
+			if self.read_only {
+				return GenErr!("nonvolatile: cannot create state \"{}\" read-only", self.name);
+			}
+			self.create(backend, fs)
+		}
+		else if !exists {
+			GenErr!("nonvolatile: state \"{}\" does not exist", self.name)
+		}
+		else {
+			self.load(backend, fs)
+		}
+	}
+
+
+	///Move the state from the directory `dir` into the backend, and open it there.
+	fn migrate(mut self, dir: &str) -> Result<State> {
+		let old = State::builder(&self.name).storage_dir(dir).lock_timeout(self.lock_timeout);
+		#[cfg(feature = "encryption")]
+		let old = StateBuilder {
+			key: self.key.clone(),
+			..old
+		};
+		let mut old = old.open()?;
+		old.inline_blobs();
+
+		self.migrate_from = None;
+		self.overwrite = true;
+		let mut state = self.open()?;
+		state.items = old.items.clone();
+		state.secrets = old.secrets.clone();
+		state.secret_salt = old.secret_salt.clone();
+		state.write_manifest()?;
+
+		let name = old.name.clone();
+		drop(old);
+		//the state has already been moved, so if this fails the old copy is just left behind
+		let _ = FsBackend::new(dir).destroy(&name);
+		Ok(state)
+	}
+
+
+	///The directory to keep the state in, if it's stored in the filesystem.
+	fn fs_dir(&self) -> Result<String> {
+		Ok(match &self.storage_dir {
+			Some(dir) => dir.clone(),
+			None if self.overwrite => storage_dir(self.kind)?,
+			None => {
+				let dir = storage_dir(self.kind)?;
+				migrate_legacy_state(&self.name, &dir)?;
+				dir
+			},
+		})
+	}
+
+
+	#[cfg(feature = "tokio")]
+	pub(crate) fn get_lock_timeout(&self) -> Duration {
+		self.lock_timeout
+	}
+
+
+	///Whether the state this would open is currently locked by someone else. Always false 
+	///for backends other than `FsBackend`.
+	#[cfg(feature = "tokio")]
+	pub(crate) fn lock_held_by_other(&self) -> bool {
+		if self.backend.is_some() {
+			return false;
+		}
+		let dir = match &self.storage_dir {
+			Some(dir) => dir.clone(),
+			None => match storage_dir(self.kind) {
+				Ok(dir) => dir,
+				Err(_) => return false,
+			},
+		};
+		let lockfile_path = canonicalize_path(format!("{}/{}/{}", dir, self.name, LOCKFILE_NAME));
+		get_lock_acquired(&lockfile_path, "").is_err()
+	}
+
+
+	///Take the lock (or in shared mode, register with the state), retrying for up to 
+	///`lock_timeout`.
+	fn acquire(&self, backend: &dyn Backend, lock_id: &str) -> Result<()> {
+		let start = Instant::now();
+		loop {
+			let err = match backend.acquire_lock(&self.name, lock_id) {
+				Ok(()) => return Ok(()),
+				Err(e) => e,
+			};
+			let elapsed = start.elapsed();
+			if elapsed >= self.lock_timeout {
+				return Err(err);
+			}
+			thread::sleep((self.lock_timeout - elapsed).min(Duration::from_millis(50)));
+		}
+	}
+
+
+	///What to write to the lockfile for a state with ID `state_id`.
+	fn lock_id(&self, state_id: &str) -> String {
+		match self.lease {
+			Some(lease) => lock::with_lease(state_id, lease),
+			None => String::from(state_id),
+		}
+	}
+
+
+	///Fill in everything about an open state that isn't stored in its manifest.
+	fn attach(&self, state: &mut State, backend: Arc<dyn Backend>, fs: Option<FsBackend>, state_id: String) {
+		//none of this is in the manifest, since it depends on where and by whom the state
+		//was opened
+		state.name = self.name.clone();
+		if let Some(fs) = &fs {
+			let path = fs.state_path(&self.name);
+			state.manifest_path = format!("{}/{}", &path, ".manifest");
+			state.tmp_manifest_path = format!("{}/{}", &path, ".manifest_tmp");
+			state.lockfile_path = fs.lockfile_path(&self.name, &state_id);
+			state.path = path;
+		}
+		state.identifier = state_id;
+		state.backend = backend;
+		//keep hand-edited manifests editable, unless asked not to
+		state.format = match (self.format, &state.editable) {
+			(Some(format), _) => format,
+			(None, Some(_)) => Format::Editable,
+			(None, None) => Format::default(),
+		};
+		state.read_only = self.read_only;
+		state.shared = self.shared;
+		//blobs aren't encrypted, and can't be written safely while others are writing
+		state.blob_threshold = match (&fs, self.shared) {
+			(Some(_), false) => self.blob_threshold,
+			_ => None,
+		};
+		#[cfg(feature = "encryption")]
+		{
+			if state.cipher.is_some() {
+				state.blob_threshold = None;
+			}
+		}
+		if let (Some(lease), Some(_), false) = (self.lease, &fs, self.read_only) {
+			state.lease = Some(lock::LeaseRenewer::start(&state.lockfile_path, &state.identifier, lease));
+		}
+	}
+
+
+	fn create(self, backend: Arc<dyn Backend>, fs: Option<FsBackend>) -> Result<State> {
+		let state_id = get_state_id()?;
+		self.acquire(&*backend, &self.lock_id(&state_id))?;
+
+		#[cfg(feature = "encryption")]
+		let cipher = match &self.key {
+			Some(key) => match ManifestCipher::new(key) {
+				Ok(cipher) => Some(cipher),
+				Err(e) => {
+					let _ = backend.release_lock(&self.name, &state_id);
+					return Err(e);
+				},
+			},
+			None => None,
+		};
+
+		let mut state = State::blank(&self.name, backend.clone());
+		#[cfg(feature = "encryption")]
+		{
+			state.cipher = cipher;
+		}
+		//don't let the items of an overwritten state come back from its old log
+		if let (Some(fs), false) = (&fs, self.write_ahead_log) {
+			if let Err(e) = fs.remove_log(&self.name) {
+				let _ = backend.release_lock(&self.name, &state_id);
+				return Err(e);
+			}
+		}
+		self.attach(&mut state, backend, fs, state_id);
+
+		// on failure, dropping the state releases the lock
+		if state.shared {
+			let write_lock_path = format!("{}/{}", &state.path, WRITE_LOCKFILE_NAME);
+			acquire_write_lock(&write_lock_path, &state.identifier)?;
+			let result = state.write_manifest();
+			let _ = remove_file(&write_lock_path);
+			result?;
+		}
+		else {
+			state.write_manifest()?;
+		}
+		Ok(state)
+	}
+
+
+	fn load(self, backend: Arc<dyn Backend>, fs: Option<FsBackend>) -> Result<State> {
+		let state_id = get_state_id()?;
+		if !self.read_only {
+			self.acquire(&*backend, &self.lock_id(&state_id))?;
+		}
+
+		let release = |e: GenericError| {
+			if !self.read_only {
+				let _ = backend.release_lock(&self.name, &state_id);
+			}
+			Err(e)
+		};
+
+		let data = match self.read_manifest(&*backend, fs.as_ref()) {
+			Ok(Some(data)) => data,
+			Ok(None) => return release(GenericError {
+				msg: format!("nonvolatile: state \"{}\" does not exist", self.name),
+			}),
+			Err(e) => return release(e),
+		};
+		let mut state = match self.parse_lazily(&*backend, data) {
+			Ok(state) => state,
+			Err(e) => return release(e),
+		};
+
+		self.attach(&mut state, backend.clone(), fs, state_id);
+		//on failure, dropping the state releases the lock
+		//the manifest is from before the state was switched to the write-ahead log
+		let items_moved = state.stores_items() && !state.items.is_empty();
+		if state.stores_items() && !items_moved {
+			state.items = backend.read_items(&self.name)?;
+		}
+		//rewrite old manifests in the current layout
+		if (items_moved || state.manifest_version < MANIFEST_VERSION) && !state.read_only {
+			state.write_manifest()?;
+			state.manifest_version = MANIFEST_VERSION;
+		}
+		//clean up blobs left behind by a crash, or dropped by `AsyncState` or `SharedState`
+		if !state.path.is_empty() && !state.read_only && !state.shared {
+			let _ = blobs::collect_garbage(&state.path, state.blobs.values());
+		}
+		Ok(state)
+	}
+
+
+	fn read_manifest(&self, backend: &dyn Backend, fs: Option<&FsBackend>) -> Result<Option<Buffer>> {
+		#[cfg(feature = "mmap")]
+		{
+			if let (true, Some(fs)) = (self.mmap, fs) {
+				let file = match File::open(format!("{}/{}", fs.state_path(&self.name), ".manifest")) {
+					Ok(file) => file,
+					Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+					Err(e) => return Err(e.into()),
+				};
+				//editable manifests get edited in place, so they're read like any other file
+				let mut head = Vec::new();
+				let _ = (&file).take(64).read_to_end(&mut head)?;
+				if editable::is_editable(&head) {
+					return Ok(backend.read_manifest(&self.name)?.map(Buffer::Owned));
+				}
+				//SAFETY: this assumes the manifest file is never truncated or written in place 
+				//while it's mapped. nonvolatile only ever replaces it with a rename, which 
+				//leaves this file (and so the mapping) intact. Editing it in place by hand or 
+				//with another tool while the state is open can crash the process with SIGBUS.
+				let map = unsafe { memmap2::Mmap::map(&file)? };
+				return Ok(Some(Buffer::Mapped(map)));
+			}
+		}
+		let _ = fs;
+		Ok(backend.read_manifest(&self.name)?.map(Buffer::Owned))
+	}
+
+
+	fn is_lazy(&self) -> bool {
+		#[cfg(feature = "mmap")]
+		{
+			if self.mmap {
+				return true;
+			}
+		}
+		self.lazy
+	}
+
+
+	///Parse the manifest, leaving the items encoded until they're needed if the state is 
+	///loaded lazily and the manifest allows it.
+	fn parse_lazily(&self, backend: &dyn Backend, data: Buffer) -> Result<State> {
+		#[cfg(feature = "encryption")]
+		let encrypted = self.key.is_some();
+		#[cfg(not(feature = "encryption"))]
+		let encrypted = false;
+		//not stored anywhere until it's attached, so dropping it on failure releases nothing
+		let mut state = State::blank(&self.name, Arc::new(MemoryBackend::new()));
+		if !self.is_lazy() || encrypted || backend.stores_items() || !LazyItems::can_index(&data) {
+			self.parse(&mut state, data.into_vec())?;
+			return Ok(state);
+		}
+		let (lazy, rest) = LazyItems::index(data)?;
+		state.replace_contents(Contents::from_manifest(&self.name, serde_json::from_str(&rest)?)?);
+		state.lazy = Some(lazy);
+		Ok(state)
+	}
+
+
+	///Parse the manifest into `state`.
+	#[cfg(not(feature = "encryption"))]
+	fn parse(&self, state: &mut State, data: Vec<u8>) -> Result<()> {
+		if data.starts_with(ENCRYPTED_MAGIC) {
+			return GenErr!("nonvolatile: state \"{}\" is encrypted, but nonvolatile was built without the `encryption` feature", self.name);
+		}
+		state.replace_contents(parse_manifest(&self.name, &data)?);
+		Ok(())
+	}
+
+
+	///Parse the manifest into `state`, decrypting it first if the state is encrypted.
+	#[cfg(feature = "encryption")]
+	fn parse(&self, state: &mut State, data: Vec<u8>) -> Result<()> {
+		let key = match &self.key {
+			Some(key) => key,
+			None if data.starts_with(ENCRYPTED_MAGIC) => {
+				return GenErr!("nonvolatile: state \"{}\" is encrypted and must be opened with State::load_encrypted", self.name);
+			},
+			None => {
+				state.replace_contents(parse_manifest(&self.name, &data)?);
+				return Ok(());
+			},
+		};
+		let (cipher, plaintext) = ManifestCipher::open(key, &data)?;
+		state.replace_contents(parse_manifest(&self.name, &plaintext)?);
+		state.cipher = Some(cipher);
+		Ok(())
+	}
+}
@@ -0,0 +1,200 @@
+/*
+the hand-editable manifest layout, see `Format::Editable`
+*/
+
+use std::collections::{HashMap, HashSet};
+use serde::{Serialize, Deserialize};
+use serde_yaml::{Mapping, Value};
+use generic_error::{Result, GenErr, GenericError};
+
+
+const HEADER: &str = "# nonvolatile: editable manifest";
+const HELP: &str = "# One setting per line, written as YAML. Comments are kept when the state is written.";
+//everything nonvolatile needs that isn't an item goes under this key
+const META_KEY: &str = "_nonvolatile";
+
+
+///What an editable manifest holds besides the items.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Meta {
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub(crate) secrets: HashMap<String, String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub(crate) secret_salt: Option<String>,
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub(crate) blobs: HashMap<String, String>,
+}
+
+
+///Whether `data` is an editable manifest.
+pub(crate) fn is_editable(data: &[u8]) -> bool {
+	data.starts_with(HEADER.as_bytes())
+}
+
+
+fn key_string(key: &Value) -> Result<String> {
+	Ok(match key {
+		Value::String(key) => key.clone(),
+		key => String::from(serde_yaml::to_string(key)?.trim_start_matches("---").trim()),
+	})
+}
+
+
+///Parse an editable manifest into its items (serialized the same way `State::set` does)
+///and everything else. Errors say which line is wrong.
+pub(crate) fn parse(text: &str) -> Result<(HashMap<String, String>, Meta)> {
+	//the error already says where the problem is
+	let document: Option<Mapping> = serde_yaml::from_str(text)?;
+	let mut items = HashMap::new();
+	let mut meta = Meta::default();
+	for (key, value) in document.unwrap_or_default() {
+		let key = key_string(&key)?;
+		if key == META_KEY {
+			meta = serde_yaml::from_value(value)?;
+		}
+		else {
+			let _ = items.insert(key, serde_yaml::to_string(&value)?);
+		}
+	}
+	Ok((items, meta))
+}
+
+
+///Write a key so that it reads back as the same string.
+fn render_key(key: &str) -> Result<String> {
+	let plain = !key.is_empty()
+		&& key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+		&& serde_yaml::from_str::<Value>(key).ok() == Some(Value::String(String::from(key)));
+	match plain {
+		true => Ok(String::from(key)),
+		false => Ok(serde_json::to_string(key)?),
+	}
+}
+
+
+///Write one entry: `key: value` for simple values, otherwise a block.
+fn render_entry(key: &str, value: &Value) -> Result<String> {
+	let rendered = serde_yaml::to_string(value)?;
+	let rendered = rendered.trim_start_matches("---").trim_matches('\n').trim_start_matches(' ');
+	let key = render_key(key)?;
+	let nested = match value {
+		Value::Mapping(mapping) => !mapping.is_empty(),
+		Value::Sequence(sequence) => !sequence.is_empty(),
+		_ => false,
+	};
+	if !nested && !rendered.contains('\n') {
+		return Ok(format!("{}: {}\n", key, rendered));
+	}
+	let mut entry = format!("{}:\n", key);
+	for line in rendered.lines() {
+		entry.push_str("  ");
+		entry.push_str(line);
+		entry.push('\n');
+	}
+	Ok(entry)
+}
+
+
+fn is_indented(line: &str) -> bool {
+	line.starts_with(' ') || line.starts_with('\t')
+}
+
+
+///Whether `line` starts a top-level entry, rather than continuing one or being a comment.
+fn starts_entry(line: &str) -> bool {
+	!(line.trim().is_empty()
+		|| is_indented(line)
+		|| line.starts_with('#')
+		|| line.starts_with("---")
+		|| line.starts_with("..."))
+}
+
+
+///Write an editable manifest holding `items` and `meta`. If there's an existing version,
+///it's updated in place: entries that haven't changed, comments and blank lines are kept
+///as they are, changed entries are rewritten where they are, and new entries are added at
+///the end.
+pub(crate) fn render(existing: Option<&str>, items: &HashMap<String, String>, meta: &Meta) -> Result<String> {
+	let mut out = String::new();
+	let mut written = HashSet::new();
+	let meta = match *meta == Meta::default() {
+		true => None,
+		false => Some(serde_yaml::to_value(meta)?),
+	};
+	let lines: Vec<&str> = existing.unwrap_or_default().lines().collect();
+	if lines.first() != Some(&HEADER) {
+		out.push_str(HEADER);
+		out.push('\n');
+		out.push_str(HELP);
+		out.push('\n');
+	}
+	let mut i = 0;
+	while i < lines.len() {
+		if !starts_entry(lines[i]) {
+			out.push_str(lines[i]);
+			out.push('\n');
+			i += 1;
+			continue;
+		}
+		//an entry goes on until the next line that isn't indented. Blank lines in between can 
+		//be part of a block scalar, unless they're at the end.
+		let mut end = i + 1;
+		while end < lines.len() && (lines[end].trim().is_empty() || is_indented(lines[end])) {
+			end += 1;
+		}
+		while lines[end - 1].trim().is_empty() {
+			end -= 1;
+		}
+		let entry = lines[i..end].join("\n");
+		i = end;
+		//with the line break at the end, which block scalars keep
+		let parsed: Option<Mapping> = serde_yaml::from_str(&format!("{}\n", entry)).ok();
+		let (key, old_value) = match parsed.and_then(|entry| entry.into_iter().next()) {
+			Some((key, value)) => (key_string(&key)?, value),
+			//not something we wrote or can read; leave it alone
+			None => {
+				out.push_str(&entry);
+				out.push('\n');
+				continue;
+			},
+		};
+		let new_value = match key.as_str() {
+			META_KEY => meta.clone(),
+			key => match items.get(key) {
+				Some(item) => Some(serde_yaml::from_str(item)?),
+				None => None,
+			},
+		};
+		if let Some(new_value) = new_value {
+			if !written.insert(key.clone()) {
+				//a duplicate entry; the first one is the one that counts
+				continue;
+			}
+			if new_value == old_value {
+				out.push_str(&entry);
+				out.push('\n');
+			}
+			else {
+				out.push_str(&render_entry(&key, &new_value)?);
+			}
+		}
+	}
+	let mut new_keys: Vec<&String> = items.keys().filter(|key| !written.contains(*key)).collect();
+	new_keys.sort();
+	for key in new_keys {
+		out.push_str(&render_entry(key, &serde_yaml::from_str(&items[key])?)?);
+	}
+	if let (Some(meta), false) = (meta, written.contains(META_KEY)) {
+		out.push_str(&render_entry(META_KEY, &meta)?);
+	}
+	Ok(out)
+}
+
+
+///Check that `key` can be stored in an editable manifest.
+pub(crate) fn check_key(key: &str) -> Result<()> {
+	if key == META_KEY {
+		return GenErr!("nonvolatile: \"{}\" is reserved in editable manifests", META_KEY);
+	}
+	Ok(())
+}
@@ -0,0 +1,330 @@
+/*
+encryption at rest for nonvolatile states
+*/
+
+use std::fmt;
+use std::fs::{read, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, Key};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use argon2::Argon2;
+use rand::random;
+use generic_error::{Result, GenErr, GenericError};
+
+use crate::ENCRYPTED_MAGIC;
+
+
+const KDF_RAW: u8 = 0;
+const KDF_ARGON2: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = ENCRYPTED_MAGIC.len() + 1 + SALT_LEN;
+
+
+///The key used to encrypt and decrypt a state's manifest.
+///
+///`Raw` keys are used as-is. `Passphrase` keys are run through Argon2id with a
+///random per-state salt, which is stored (unencrypted) alongside the manifest.
+///`Keyfile` keys are 32 random bytes kept in a file; if the file doesn't exist it is
+///generated (with 0600 permissions on Unix), and a keyfile readable by other users
+///is refused. A state must always be opened with the same kind of key it was created with.
+///
+///### Example
+///
+///```rust
+///let key = EncryptionKey::Passphrase(String::from("correct horse battery staple"));
+///let mut state = State::load_else_create_encrypted("tokens", &key)?;
+///state.set("api_token", "hunter2")?;
+///```
+#[derive(Clone)]
+pub enum EncryptionKey {
+	Raw([u8; 32]),
+	Passphrase(String),
+	Keyfile(PathBuf),
+}
+
+
+impl fmt::Debug for EncryptionKey {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			EncryptionKey::Raw(_) => write!(f, "EncryptionKey::Raw(<redacted>)"),
+			EncryptionKey::Passphrase(_) => write!(f, "EncryptionKey::Passphrase(<redacted>)"),
+			EncryptionKey::Keyfile(path) => write!(f, "EncryptionKey::Keyfile({:?})", path),
+		}
+	}
+}
+
+
+///The derived key for an open state, along with the header that gets written in front
+///of every encrypted manifest.
+pub(crate) struct ManifestCipher {
+	key: [u8; 32],
+	header: Vec<u8>,
+}
+
+
+impl fmt::Debug for ManifestCipher {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "ManifestCipher(<redacted>)")
+	}
+}
+
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32]> {
+	let mut key = [0u8; 32];
+	if let Err(e) = Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key) {
+		return GenErr!("nonvolatile: failed to derive key from passphrase: {}", e);
+	}
+	Ok(key)
+}
+
+
+#[cfg(unix)]
+fn check_keyfile_permissions(path: &Path) -> Result<()> {
+	use std::os::unix::fs::PermissionsExt;
+	let mode = std::fs::metadata(path)?.permissions().mode();
+	if mode & 0o077 != 0 {
+		return GenErr!("nonvolatile: keyfile {} has permissions {:o}, but must not be accessible by other users (use 0600)", path.display(), mode & 0o777);
+	}
+	Ok(())
+}
+
+
+#[cfg(not(unix))]
+fn check_keyfile_permissions(_path: &Path) -> Result<()> {
+	Ok(())
+}
+
+
+///Read the key stored in a keyfile, generating a new keyfile if there isn't one.
+fn read_keyfile(path: &Path) -> Result<[u8; 32]> {
+	if !path.exists() {
+		let mut options = OpenOptions::new();
+		options.write(true).create_new(true);
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::OpenOptionsExt;
+			options.mode(0o600);
+		}
+		let mut file = options.open(path)?;
+		file.write_all(&random::<[u8; 32]>())?;
+		file.sync_all()?;
+	}
+	check_keyfile_permissions(path)?;
+	let data = read(path)?;
+	if data.len() != 32 {
+		return GenErr!("nonvolatile: keyfile {} should contain exactly 32 bytes, but contains {}", path.display(), data.len());
+	}
+	let mut key = [0u8; 32];
+	key.copy_from_slice(&data);
+	Ok(key)
+}
+
+
+fn build_header(kdf: u8, salt: &[u8]) -> Vec<u8> {
+	let mut header = Vec::with_capacity(HEADER_LEN);
+	header.extend_from_slice(ENCRYPTED_MAGIC);
+	header.push(kdf);
+	header.extend_from_slice(salt);
+	header
+}
+
+
+impl ManifestCipher {
+
+	///Set up a cipher for a brand new state. Passphrases get a fresh salt.
+	pub(crate) fn new(key: &EncryptionKey) -> Result<ManifestCipher> {
+		match key {
+			EncryptionKey::Raw(raw) => Ok(ManifestCipher {
+				key: *raw,
+				header: build_header(KDF_RAW, &[0u8; SALT_LEN]),
+			}),
+			EncryptionKey::Keyfile(path) => Ok(ManifestCipher {
+				key: read_keyfile(path)?,
+				header: build_header(KDF_RAW, &[0u8; SALT_LEN]),
+			}),
+			EncryptionKey::Passphrase(passphrase) => {
+				let salt = random::<[u8; SALT_LEN]>();
+				Ok(ManifestCipher {
+					key: derive_key(passphrase, &salt)?,
+					header: build_header(KDF_ARGON2, &salt),
+				})
+			},
+		}
+	}
+
+
+	///Decrypt an existing manifest, returning the plaintext and a cipher that will keep
+	///writing the manifest with the same key and salt.
+	pub(crate) fn open(key: &EncryptionKey, data: &[u8]) -> Result<(ManifestCipher, Vec<u8>)> {
+		if !data.starts_with(ENCRYPTED_MAGIC) {
+			return GenErr!("nonvolatile: state is not encrypted; use State::load instead of State::load_encrypted");
+		}
+		if data.len() < HEADER_LEN + NONCE_LEN {
+			return GenErr!("nonvolatile: encrypted manifest is truncated ({} bytes)", data.len());
+		}
+		let header = &data[..HEADER_LEN];
+		let kdf = header[ENCRYPTED_MAGIC.len()];
+		let salt = &header[ENCRYPTED_MAGIC.len() + 1..];
+
+		let derived = match (kdf, key) {
+			(KDF_RAW, EncryptionKey::Raw(raw)) => *raw,
+			(KDF_RAW, EncryptionKey::Keyfile(path)) => read_keyfile(path)?,
+			(KDF_ARGON2, EncryptionKey::Passphrase(passphrase)) => derive_key(passphrase, salt)?,
+			(KDF_RAW, EncryptionKey::Passphrase(_)) => {
+				return GenErr!("nonvolatile: state was encrypted with a raw key, but a passphrase was given");
+			},
+			(KDF_ARGON2, _) => {
+				return GenErr!("nonvolatile: state was encrypted with a passphrase, but a raw key or keyfile was given");
+			},
+			(other, _) => return GenErr!("nonvolatile: unknown key derivation scheme {} in encrypted manifest", other),
+		};
+
+		let cipher = ManifestCipher {
+			key: derived,
+			header: header.to_vec(),
+		};
+		let plaintext = match cipher.unseal(data) {
+			Ok(plaintext) => plaintext,
+			Err(_) => return GenErr!("nonvolatile: failed to decrypt state: wrong key, or the manifest has been tampered with"),
+		};
+		Ok((cipher, plaintext))
+	}
+
+
+	///Decrypt a manifest written with this cipher.
+	pub(crate) fn unseal(&self, data: &[u8]) -> Result<Vec<u8>> {
+		if !data.starts_with(&self.header) || data.len() < HEADER_LEN + NONCE_LEN {
+			return GenErr!("nonvolatile: encrypted manifest was rewritten with a different key");
+		}
+		let (header, rest) = data.split_at(HEADER_LEN);
+		let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+		let aead = XChaCha20Poly1305::new(Key::from_slice(&self.key));
+		let payload = Payload {
+			msg: ciphertext,
+			aad: header,
+		};
+		match aead.decrypt(XNonce::from_slice(nonce), payload) {
+			Ok(plaintext) => Ok(plaintext),
+			Err(_) => GenErr!("nonvolatile: failed to decrypt state: the manifest has been tampered with"),
+		}
+	}
+
+
+	///Encrypt a serialized manifest, producing `header || nonce || ciphertext`.
+	pub(crate) fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+		let nonce = random::<[u8; NONCE_LEN]>();
+		let aead = XChaCha20Poly1305::new(Key::from_slice(&self.key));
+		let payload = Payload {
+			msg: plaintext,
+			aad: &self.header,
+		};
+		let ciphertext = match aead.encrypt(XNonce::from_slice(&nonce), payload) {
+			Ok(ciphertext) => ciphertext,
+			Err(_) => return GenErr!("nonvolatile: failed to encrypt state manifest"),
+		};
+		let mut data = Vec::with_capacity(self.header.len() + NONCE_LEN + ciphertext.len());
+		data.extend_from_slice(&self.header);
+		data.extend_from_slice(&nonce);
+		data.extend_from_slice(&ciphertext);
+		Ok(data)
+	}
+}
+
+
+///The key used to encrypt individual secret values (see `State::set_secret`).
+///
+///Passphrases are derived with the state's own salt, so the same passphrase gives a
+///different key for every state.
+pub(crate) struct SecretCipher {
+	key: [u8; 32],
+}
+
+
+impl fmt::Debug for SecretCipher {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "SecretCipher(<redacted>)")
+	}
+}
+
+
+fn to_hex(data: &[u8]) -> String {
+	data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+//`is_multiple_of` would need Rust 1.87
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+	if s.len() % 2 != 0 {
+		return None;
+	}
+	(0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+
+///Generate a new hex-encoded salt for deriving secret keys from passphrases.
+pub(crate) fn new_salt() -> String {
+	to_hex(&random::<[u8; SALT_LEN]>())
+}
+
+
+impl SecretCipher {
+
+	pub(crate) fn new(key: &EncryptionKey, salt: &str) -> Result<SecretCipher> {
+		let key = match key {
+			EncryptionKey::Raw(raw) => *raw,
+			EncryptionKey::Keyfile(path) => read_keyfile(path)?,
+			EncryptionKey::Passphrase(passphrase) => {
+				let salt = match from_hex(salt) {
+					Some(salt) => salt,
+					None => return GenErr!("nonvolatile: state has a corrupted secret salt \"{}\"", salt),
+				};
+				derive_key(passphrase, &salt)?
+			},
+		};
+		Ok(SecretCipher {
+			key,
+		})
+	}
+
+
+	///Encrypt a single value, bound to the name it's stored under so it can't be swapped
+	///with another entry. Returns `hex(nonce || ciphertext)`.
+	pub(crate) fn seal(&self, var: &str, plaintext: &str) -> Result<String> {
+		let nonce = random::<[u8; NONCE_LEN]>();
+		let aead = XChaCha20Poly1305::new(Key::from_slice(&self.key));
+		let payload = Payload {
+			msg: plaintext.as_bytes(),
+			aad: var.as_bytes(),
+		};
+		let ciphertext = match aead.encrypt(XNonce::from_slice(&nonce), payload) {
+			Ok(ciphertext) => ciphertext,
+			Err(_) => return GenErr!("nonvolatile: failed to encrypt secret \"{}\"", var),
+		};
+		Ok(format!("{}{}", to_hex(&nonce), to_hex(&ciphertext)))
+	}
+
+
+	pub(crate) fn open(&self, var: &str, sealed: &str) -> Result<String> {
+		let data = match from_hex(sealed) {
+			Some(data) if data.len() >= NONCE_LEN => data,
+			_ => return GenErr!("nonvolatile: secret \"{}\" is corrupted", var),
+		};
+		let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+		let aead = XChaCha20Poly1305::new(Key::from_slice(&self.key));
+		let payload = Payload {
+			msg: ciphertext,
+			aad: var.as_bytes(),
+		};
+		let plaintext = match aead.decrypt(XNonce::from_slice(nonce), payload) {
+			Ok(plaintext) => plaintext,
+			Err(_) => return GenErr!("nonvolatile: failed to decrypt secret \"{}\": wrong key, or the value has been tampered with", var),
+		};
+		match String::from_utf8(plaintext) {
+			Ok(plaintext) => Ok(plaintext),
+			Err(_) => GenErr!("nonvolatile: secret \"{}\" is corrupted", var),
+		}
+	}
+}
@@ -0,0 +1,181 @@
+/*
+reading settings out of TOML, JSON, INI and .env files
+*/
+
+use std::fs::read_to_string;
+use std::path::Path;
+use serde_json::Value;
+use generic_error::{Result, GenErr, GenericError};
+
+
+///The format of a file read by `State::import_file`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportFormat {
+	Toml,
+	Json,
+	///`key = value` pairs, grouped into `[section]`s.
+	Ini,
+	///`KEY=value` pairs, as read by shells and dotenv libraries.
+	Env,
+}
+
+
+impl ImportFormat {
+
+	///Guess the format of a file from its name: `.toml`, `.json`, `.ini`/`.cfg`/`.conf`, or
+	///`.env` (including names like `.env.local`).
+	pub fn from_path(path: &str) -> Option<ImportFormat> {
+		let file_name = Path::new(path).file_name()?.to_string_lossy().to_lowercase();
+		if file_name == ".env" || file_name.starts_with(".env.") {
+			return Some(ImportFormat::Env);
+		}
+		match Path::new(&file_name).extension()?.to_str()? {
+			"toml" => Some(ImportFormat::Toml),
+			"json" => Some(ImportFormat::Json),
+			"ini" | "cfg" | "conf" => Some(ImportFormat::Ini),
+			"env" => Some(ImportFormat::Env),
+			_ => None,
+		}
+	}
+}
+
+
+///What `State::import_file` does with keys that are already in the state with a different value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeMode {
+	///Replace the existing values.
+	Overwrite,
+	///Leave the existing values alone, and only import keys that are new.
+	KeepExisting,
+	///Don't import anything, and return an error listing the conflicting keys.
+	Fail,
+}
+
+
+///What `State::import_file` did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+	///The keys that were written.
+	pub imported: Vec<String>,
+	///The keys that were already in the state with a different value. Whether they were
+	///overwritten depends on the `MergeMode`.
+	pub conflicts: Vec<String>,
+}
+
+
+///Flatten nested tables into dotted keys, e.g. `server.port`. Anything else, including
+///arrays, is kept as a single value.
+fn flatten(prefix: &str, value: Value, out: &mut Vec<(String, Value)>) {
+	match value {
+		Value::Object(table) => {
+			for (key, value) in table {
+				let key = match prefix {
+					"" => key,
+					_ => format!("{}.{}", prefix, key),
+				};
+				flatten(&key, value, out);
+			}
+		},
+		value => out.push((String::from(prefix), value)),
+	}
+}
+
+
+fn from_toml(value: toml::Value) -> Value {
+	match value {
+		toml::Value::String(s) => Value::String(s),
+		toml::Value::Integer(i) => Value::from(i),
+		toml::Value::Float(f) => Value::from(f),
+		toml::Value::Boolean(b) => Value::Bool(b),
+		toml::Value::Datetime(d) => Value::String(d.to_string()),
+		toml::Value::Array(array) => Value::Array(array.into_iter().map(from_toml).collect()),
+		toml::Value::Table(table) => Value::Object(table.into_iter().map(|(k, v)| (k, from_toml(v))).collect()),
+	}
+}
+
+
+///A value from an INI or .env file, which has no types of its own. Quoted values are strings;
+///anything else that reads as a YAML scalar (a number, `true`, ...) gets that type, so that
+///`State::get` can read `port = 8080` as a number.
+fn from_untyped(raw: &str) -> Value {
+	let raw = raw.trim();
+	for quote in &['"', '\''] {
+		if raw.len() >= 2 && raw.starts_with(*quote) && raw.ends_with(*quote) {
+			return Value::String(String::from(&raw[1..raw.len() - 1]));
+		}
+	}
+	match serde_yaml::from_str::<Value>(raw) {
+		Ok(Value::Null) | Ok(Value::Array(_)) | Ok(Value::Object(_)) | Err(_) => Value::String(String::from(raw)),
+		Ok(value) => value,
+	}
+}
+
+
+fn parse_ini(path: &str, text: &str) -> Result<Vec<(String, Value)>> {
+	let mut section = String::new();
+	let mut out = Vec::new();
+	for (i, line) in text.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+			continue;
+		}
+		if line.starts_with('[') && line.ends_with(']') {
+			section = String::from(line[1..line.len() - 1].trim());
+			continue;
+		}
+		let split = match line.find(['=', ':']) {
+			Some(split) => split,
+			None => return GenErr!("nonvolatile: {} line {}: expected `key = value`", path, i + 1),
+		};
+		let key = line[..split].trim();
+		let key = match section.as_str() {
+			"" => String::from(key),
+			_ => format!("{}.{}", section, key),
+		};
+		out.push((key, from_untyped(&line[split + 1..])));
+	}
+	Ok(out)
+}
+
+
+fn parse_env(path: &str, text: &str) -> Result<Vec<(String, Value)>> {
+	let mut out = Vec::new();
+	for (i, line) in text.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let line = line.strip_prefix("export ").unwrap_or(line);
+		let split = match line.find('=') {
+			Some(split) => split,
+			None => return GenErr!("nonvolatile: {} line {}: expected `KEY=value`", path, i + 1),
+		};
+		out.push((String::from(line[..split].trim()), from_untyped(&line[split + 1..])));
+	}
+	Ok(out)
+}
+
+
+///Read the file at `path` as a list of keys and values, with nested tables flattened.
+pub(crate) fn read_file(path: &str, format: ImportFormat) -> Result<Vec<(String, Value)>> {
+	let text = read_to_string(path)?;
+	let mut out = Vec::new();
+	match format {
+		ImportFormat::Toml => {
+			let table: toml::Table = match toml::from_str(&text) {
+				Ok(table) => table,
+				Err(e) => return GenErr!("nonvolatile: {} is not valid TOML: {}", path, e),
+			};
+			flatten("", from_toml(toml::Value::Table(table)), &mut out);
+		},
+		ImportFormat::Json => {
+			match serde_json::from_str(&text)? {
+				Value::Object(table) => flatten("", Value::Object(table), &mut out),
+				_ => return GenErr!("nonvolatile: {} does not contain a JSON object", path),
+			}
+		},
+		ImportFormat::Ini => out = parse_ini(path, &text)?,
+		ImportFormat::Env => out = parse_env(path, &text)?,
+	}
+	Ok(out)
+}
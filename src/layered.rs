@@ -0,0 +1,210 @@
+/*
+resolving settings from the environment, the user's state, a system-wide state and defaults
+*/
+
+use std::collections::HashMap;
+use std::env;
+use serde::{Serialize, Deserialize};
+use generic_error::Result;
+
+use crate::{State, FsBackend, Backend, get_storage_dir, STORAGE_SUB_DIR};
+
+
+#[cfg(not(windows))]
+const SYSTEM_STORAGE_DIR: &str = "/etc";
+#[cfg(windows)]
+const SYSTEM_STORAGE_DIR: &str = "C:/ProgramData";
+
+
+///The layers of a `LayeredState`, from the one that wins to the one that's used last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layer {
+	///An environment variable, e.g. `MYAPP_THEME` for the key `theme` of the state `myapp`.
+	Env,
+	///The user's own state, which is the only layer that gets written to.
+	User,
+	///The system-wide state, under `/etc/rust_nonvolatile` (`C:/ProgramData/rust_nonvolatile`
+	///on Windows).
+	System,
+	///A default set with `LayeredState::set_default`.
+	Default,
+}
+
+
+///A state that looks each key up in several layers, for settings that an administrator or
+///the environment should be able to override. See `Layer` for the order.
+///
+///Only the user's state is ever written to: `set` and `delete` change it, and a value
+///deleted from it may still be supplied by a lower layer. The system-wide state is opened
+///read-only, and is skipped if it doesn't exist. It's normally owned by root, so it needs to
+///be created with permissions that let other users read it (see `set_permissions`).
+///
+///Values from environment variables are parsed as YAML, so `MYAPP_RETRIES=3` can be read as
+///a number and `MYAPP_THEME=dark` as a string.
+///
+///### Example
+///
+///```rust
+///let mut settings = LayeredState::open("myapp")?;
+///settings.set_default("theme", "light")?;
+///if let Some((theme, layer)) = settings.get_with_layer::<String>("theme") {
+///    println!("theme is {} (from {:?})", theme, layer);
+///}
+///settings.set("theme", "dark")?;
+///```
+#[derive(Debug)]
+pub struct LayeredState {
+	env_prefix: String,
+	user: State,
+	system: Option<State>,
+	defaults: HashMap<String, String>,
+}
+
+
+///The name of the environment variable for `key`: `prefix_KEY`, with anything that isn't a
+///letter or digit replaced by an underscore.
+fn env_var_name(prefix: &str, key: &str) -> String {
+	format!("{}_{}", prefix, key)
+		.chars()
+		.map(|c| match c.is_ascii_alphanumeric() {
+			true => c.to_ascii_uppercase(),
+			false => '_',
+		})
+		.collect()
+}
+
+
+impl LayeredState {
+
+	///Open the layers of the state with the given name: the user's state in the default
+	///storage location (created if it doesn't exist), and the system-wide state if there is
+	///one. Environment variables are prefixed with the name in upper case.
+	pub fn open(name: &str) -> Result<LayeredState> {
+		let system_dir = format!("{}/{}", SYSTEM_STORAGE_DIR, STORAGE_SUB_DIR);
+		LayeredState::open_from(name, &get_storage_dir()?, &system_dir)
+	}
+
+
+	///Open the layers of the state with the given name, with the user's state kept in
+	///`storage_path` and the system-wide state in `system_path`. See `open`.
+	pub fn open_from(name: &str, storage_path: &str, system_path: &str) -> Result<LayeredState> {
+		let system = match FsBackend::new(system_path).exists(name)? {
+			true => Some(State::builder(name).storage_dir(system_path).read_only(true).open()?),
+			false => None,
+		};
+		let user = State::load_else_create_from(name, storage_path)?;
+		Ok(LayeredState::from_layers(user, system))
+	}
+
+
+	///Layer already open states, e.g. ones opened with `State::builder`. `system` should be
+	///opened read-only, since it's never written to. The environment variable prefix is the
+	///user state's name.
+	pub fn from_layers(user: State, system: Option<State>) -> LayeredState {
+		LayeredState {
+			env_prefix: user.name.clone(),
+			user,
+			system,
+			defaults: HashMap::new(),
+		}
+	}
+
+
+	///Use a different prefix for environment variables, e.g. `"MYAPP"` to look `theme` up
+	///in `MYAPP_THEME`.
+	pub fn env_prefix(mut self, prefix: &str) -> LayeredState {
+		self.env_prefix = String::from(prefix);
+		self
+	}
+
+
+	///Set the value used for `key` when no other layer has one. Defaults aren't stored
+	///anywhere, so they need to be set every time the state is opened.
+	pub fn set_default<T>(&mut self, key: &str, value: T) -> Result<()> where T: Serialize {
+		let _ = self.defaults.insert(String::from(key), serde_yaml::to_string(&value)?);
+		Ok(())
+	}
+
+
+	///Look `key` up in each layer in turn, and return the first value found along with the
+	///layer it came from. As with `State::get`, a value of the wrong type counts as missing.
+	pub fn get_with_layer<T>(&self, key: &str) -> Option<(T, Layer)> where for<'a> T: Deserialize<'a> {
+		if let Ok(value) = env::var(env_var_name(&self.env_prefix, key)) {
+			if let Ok(value) = serde_yaml::from_str(&value) {
+				return Some((value, Layer::Env));
+			}
+		}
+		if let Some(value) = self.user.get(key) {
+			return Some((value, Layer::User));
+		}
+		if let Some(value) = self.system.as_ref().and_then(|system| system.get(key)) {
+			return Some((value, Layer::System));
+		}
+		let value = serde_yaml::from_str(self.defaults.get(key)?).ok()?;
+		Some((value, Layer::Default))
+	}
+
+
+	///Look `key` up in each layer in turn. See `get_with_layer`.
+	pub fn get<T>(&self, key: &str) -> Option<T> where for<'a> T: Deserialize<'a> {
+		self.get_with_layer(key).map(|(value, _)| value)
+	}
+
+
+	///Which layer holds a value for `key`, if any.
+	///
+	///This only looks at whether a value is there, not whether it can be read as any 
+	///particular type. If the value in the first layer has the wrong type for a `get` (e.g. an
+	///environment variable that doesn't parse), `get_with_layer` skips it and falls through 
+	///to the next layer, but `layer_of` still reports the first one. Use `get_with_layer` to 
+	///find out where a usable value came from.
+	pub fn layer_of(&self, key: &str) -> Option<Layer> {
+		if env::var_os(env_var_name(&self.env_prefix, key)).is_some() {
+			Some(Layer::Env)
+		}
+		else if self.user.has(key) {
+			Some(Layer::User)
+		}
+		else if self.system.as_ref().map(|system| system.has(key)) == Some(true) {
+			Some(Layer::System)
+		}
+		else if self.defaults.contains_key(key) {
+			Some(Layer::Default)
+		}
+		else {
+			None
+		}
+	}
+
+
+	///Set `key` in the user's state. An environment variable for the same key still takes
+	///precedence. See `State::set`.
+	pub fn set<T>(&mut self, key: &str, value: T) -> Result<()> where T: Serialize {
+		self.user.set(key, value)
+	}
+
+
+	///Delete `key` from the user's state, so that it's supplied by a lower layer again (if
+	///any has it). See `State::delete`.
+	pub fn delete(&mut self, key: &str) -> Result<()> {
+		self.user.delete(key)
+	}
+
+
+	///The user's state.
+	pub fn user(&self) -> &State {
+		&self.user
+	}
+
+
+	///The user's state, for anything `LayeredState` doesn't cover.
+	pub fn user_mut(&mut self) -> &mut State {
+		&mut self.user
+	}
+
+
+	///The system-wide state, if there is one.
+	pub fn system(&self) -> Option<&State> {
+		self.system.as_ref()
+	}
+}
@@ -0,0 +1,157 @@
+/*
+indexing a manifest's items at load time, and decoding each one the first time it's used
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Mutex;
+use serde_json::value::RawValue;
+use generic_error::{Result, GenErr, GenericError};
+
+
+///The bytes of a manifest, either read into memory or mapped straight from the file.
+pub(crate) enum Buffer {
+	Owned(Vec<u8>),
+	#[cfg(feature = "mmap")]
+	Mapped(memmap2::Mmap),
+}
+
+
+impl Buffer {
+
+	pub(crate) fn into_vec(self) -> Vec<u8> {
+		match self {
+			Buffer::Owned(data) => data,
+			#[cfg(feature = "mmap")]
+			Buffer::Mapped(map) => map.to_vec(),
+		}
+	}
+}
+
+
+impl Deref for Buffer {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		match self {
+			Buffer::Owned(data) => data,
+			#[cfg(feature = "mmap")]
+			Buffer::Mapped(map) => map,
+		}
+	}
+}
+
+
+///The items of a JSON manifest, still encoded. Each value is decoded the first time it's
+///asked for, and kept from then on.
+pub(crate) struct LazyItems {
+	buffer: Buffer,
+	//where each item's encoded value is in the buffer
+	index: HashMap<String, (usize, usize)>,
+	decoded: Mutex<HashMap<String, String>>,
+}
+
+
+impl fmt::Debug for LazyItems {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("LazyItems")
+			.field("items", &self.index.len())
+			.field("decoded", &self.decoded().len())
+			.finish()
+	}
+}
+
+
+impl LazyItems {
+
+	///Whether the manifest in `data` is JSON, and so can be indexed. YAML manifests have to be
+	///parsed up front.
+	pub(crate) fn can_index(data: &[u8]) -> bool {
+		data.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{')
+	}
+
+
+	///Index the items of the JSON manifest in `buffer`. Along with the index, returns the rest
+	///of the manifest, to be parsed as usual.
+	pub(crate) fn index(buffer: Buffer) -> Result<(LazyItems, String)> {
+		let mut index = HashMap::new();
+		let mut decoded = HashMap::new();
+		let mut rest = Vec::new();
+		{
+			let start = buffer.as_ptr() as usize;
+			let manifest: HashMap<String, &RawValue> = serde_json::from_slice(&buffer)?;
+			for (field, value) in manifest {
+				if field != "items" {
+					rest.push(format!("{}:{}", serde_json::to_string(&field)?, value.get()));
+					continue;
+				}
+				let items: HashMap<String, &RawValue> = serde_json::from_str(value.get())?;
+				for (key, value) in items {
+					let value = value.get();
+					if !value.starts_with('"') {
+						return GenErr!("nonvolatile: item \"{}\" in manifest is not a string", key);
+					}
+					//parsing the manifest checked every value, except whether its \u escapes are
+					//valid UTF-16. Decode those now, so that decoding can't fail later.
+					if value.contains("\\u") {
+						let _ = decoded.insert(key.clone(), serde_json::from_str::<String>(value)?);
+					}
+					let offset = value.as_ptr() as usize - start;
+					let _ = index.insert(key, (offset, offset + value.len()));
+				}
+			}
+		}
+		let lazy = LazyItems {
+			buffer,
+			index,
+			decoded: Mutex::new(decoded),
+		};
+		Ok((lazy, format!("{{{}}}", rest.join(","))))
+	}
+
+
+	fn decoded(&self) -> std::sync::MutexGuard<'_, HashMap<String, String>> {
+		match self.decoded.lock() {
+			Ok(decoded) => decoded,
+			Err(poisoned) => poisoned.into_inner(),
+		}
+	}
+
+
+	fn decode(&self, key: &str) -> Option<String> {
+		let (start, end) = *self.index.get(key)?;
+		serde_json::from_slice(&self.buffer[start..end]).ok()
+	}
+
+
+	///The (still serialized) value of the item `key`, if there is one.
+	pub(crate) fn get(&self, key: &str) -> Option<String> {
+		let mut decoded = self.decoded();
+		if let Some(value) = decoded.get(key) {
+			return Some(value.clone());
+		}
+		let value = self.decode(key)?;
+		let _ = decoded.insert(String::from(key), value.clone());
+		Some(value)
+	}
+
+
+	pub(crate) fn contains_key(&self, key: &str) -> bool {
+		self.index.contains_key(key)
+	}
+
+
+	///Decode every item that hasn't been decoded yet, and hand them all over.
+	pub(crate) fn into_items(self) -> HashMap<String, String> {
+		let mut items = self.decoded().clone();
+		for key in self.index.keys() {
+			if !items.contains_key(key) {
+				if let Some(value) = self.decode(key) {
+					let _ = items.insert(key.clone(), value);
+				}
+			}
+		}
+		items
+	}
+}
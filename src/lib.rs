@@ -14,7 +14,9 @@ August 2019
 //!platform being used. Values persist until they are overwritten, and can be 
 //!accessed by any program that loads the state with that name. `State` instances
 //!are exclusive (i.e., two programs or two instances of the same program cannot 
-//!have the same `State` open at the same time).
+//!have the same `State` open at the same time). The lock is released when the `State` is 
+//!dropped; call `State::close` instead to find out whether that (and getting everything onto 
+//!the disk) worked.
 //!
 //!Most of the builtin types, and any type that implements `serde::Serialize`/`Deserialize` 
 //!may be passed into and read from `State::set` and `State::get`.
@@ -50,21 +52,129 @@ August 2019
 //!# Notes
 //!
 //!By default, state for a given name will be stored in 
-//!`$HOME/.local/rust_nonvolatile/<name>` for Linux and MacOS systems, and 
-//!`%appdata%\rust_nonvolatile\<name>` for Windows systems. If $HOME or %appdata% 
-//!are not defined in the program environment, then nonvolatile will fall back to 
-//!`/etc` and `C:\ProgramData` for Linux/MacOS and Windows respectively. 
+//!`$XDG_CONFIG_HOME/rust_nonvolatile/<name>` (usually `~/.config/rust_nonvolatile/<name>`) 
+//!for Linux, `$HOME/.local/rust_nonvolatile/<name>` for MacOS, and 
+//!`%appdata%\rust_nonvolatile\<name>` for Windows systems. If the `NONVOLATILE_DIR` environment 
+//!variable is set, states are stored in `$NONVOLATILE_DIR/<name>` instead. States that hold data 
+//!rather than configuration can be kept under `$XDG_DATA_HOME` or `$XDG_STATE_HOME` by passing 
+//!`nonvolatile::storage_dir(StateKind::Data)` (or `StateKind::State`) to the `*_from` functions.
+//!
+//!Older versions stored states in `$HOME/.local/rust_nonvolatile` on Linux. `load` moves a state 
+//!from there to the new location the first time it's loaded.
+//!
+//!The manifest only holds a state's name, items and secrets. Where the state is stored is 
+//!worked out each time it's loaded, so a state's directory can be moved to another storage 
+//!location as a whole. Manifests written by older versions, which also held paths and the 
+//!owner's lock ID, are rewritten in the new layout the first time they're loaded.
+//!
+//!To rename or copy a state, or move it to another storage location, use `State::rename`, 
+//!`State::copy` and `State::move_to`. These fail if the state (or the destination) is open, 
+//!bring along everything the state is stored in, and only replace an existing state when asked to.
 //!
 //!If your environment is unreliable, or you have a location where you'd rather keep settings
 //!and configuration, the default storage location can be overridden using the 
 //!`*_from` functions (`new_from` instead of `new`, `load_from` instead of `load`, 
 //!`load_else_create_from` instead of `load_else_create`). 
 //!
+//!For anything the functions above don't cover, `State::builder` gives access to all of the 
+//!options for opening a state (storage location, creating if missing, manifest format, waiting 
+//!for the lock, read-only access, and encryption):
+//!
+//!```rust
+//!let state = State::builder("my_state")
+//!    .storage_dir("/srv/my_app")
+//!    .create_if_missing(true)
+//!    .lock_timeout(Duration::from_secs(5))
+//!    .open()?;
+//!```
+//!
+//!To use a state from several threads, turn it into a `SharedState` with `State::into_shared`. 
+//!`SharedState` is `Clone + Send + Sync`, and writes to disk from a background thread so that 
+//!`get` never waits on file I/O. Use `SharedState::flush` to wait for (and check) pending writes,
+//!and `SharedState::close` to do the same and release the state once it's no longer needed.
+//!
+//!With the `tokio` feature enabled, `AsyncState` provides `async` versions of `new`, `load`, 
+//!`load_else_create`, `set`, `delete` and `flush` that don't block the runtime, and 
+//!`AsyncState::open` accepts a `StateBuilder` for everything else.
+//!
+//!For tests, `State::in_memory` creates a state that never touches the disk, and `State::temporary` 
+//!creates one in a temporary directory that's deleted when the state is dropped. Neither can clash 
+//!with a real state (or another test) of the same name.
+//!
+//!States are stored in the filesystem by `FsBackend`. To keep them somewhere else, implement the 
+//!`Backend` trait and pass it to `StateBuilder::backend`. With the `sqlite` feature enabled, 
+//!`SqliteBackend` keeps states in a single SQLite database, storing each item in its own row so 
+//!that `set` doesn't have to rewrite every other item; `StateBuilder::migrate_from` moves existing 
+//!states into it.
+//!
+//!Staying in the filesystem, `StateBuilder::write_ahead_log` gets the same effect: each `set` 
+//!and `delete` appends a record to a log next to the manifest, which is replayed over the last 
+//!snapshot of the items on load and compacted into a new snapshot once it grows too big.
+//!
+//!Big values (e.g. caches) can be kept out of the manifest altogether with 
+//!`StateBuilder::blob_threshold`: values over the threshold are written to files of their own, 
+//!named after a hash of their contents, which are read on `get` and removed once nothing refers 
+//!to them anymore.
+//!
+//!To open big, read-mostly states quickly, `StateBuilder::lazy` only indexes the items of a JSON 
+//!manifest on load and decodes each one the first time it's used. With the `mmap` feature 
+//!enabled, `StateBuilder::mmap` also maps the manifest into memory instead of reading it.
+//!
+//!For settings that the environment or an administrator should be able to override, 
+//!`LayeredState` looks each key up in environment variables (`MYAPP_THEME` for the key `theme`), 
+//!then the user's state, then a system-wide state under `/etc/rust_nonvolatile`, and finally 
+//!defaults set in code. Only the user's state is written to.
+//!
+//!Settings in existing TOML, JSON, INI or `.env` files can be brought in with `State::import_file`, 
+//!which flattens nested tables into dotted keys (`server.port`) and reports any keys that 
+//!conflict with ones already in the state.
+//!
+//!Manifests normally hold a serialized `State`, which isn't meant to be read by people. For 
+//!settings that are edited by hand, `StateBuilder::format(Format::Editable)` writes a plain YAML 
+//!document with one entry per item instead, keeping comments and unchanged entries as they are. 
+//!`State::reload` picks up hand edits, and reports the line of any syntax error.
+//!
+//!`State` instances are normally exclusive, but a state opened with `State::builder(name).shared(true)` 
+//!can be open in several processes at once. In shared mode, each `set` and `delete` briefly takes 
+//!a write lock, re-reads the manifest, and applies only its own change before writing it back, so 
+//!processes writing different keys never lose each other's updates. `State::reload` picks up 
+//!changes made by other processes.
+//!
+//!If a program hangs or crashes while it has a state open, `nonvolatile::lock_info` tells you 
+//!which process (and host) holds the lock and since when. A lock left by a process that has 
+//!exited is taken over automatically by the next `load`; `State::force_unlock` and 
+//!`State::load_steal` can also take over from one that's still running.
+//!Lockfiles record the owner's hostname, so if the storage directory is shared between machines 
+//!(e.g. over NFS), a lock held from another host is never mistaken for a dead local process. 
+//!To let others take over from an owner that may have crashed on an unreachable host, open the 
+//!state with `StateBuilder::lease`: the owner then keeps renewing a lease on the lock, and anyone 
+//!can take the lock over once the lease runs out.
+//!
 //!Be careful to be consistent 
 //!with the storage location! If you use a state from one location during one instance
 //!of your program, and then use a state from a different location during the next,
 //!you will be left with two non-matching states with the same name in different places.
 //!
+//!On Unix, state directories are created with mode `0700` and state files with `0600`, and 
+//!loading a state whose directory is world-writable or owned by another user is refused. 
+//!States created with broader modes (e.g. by older versions) are narrowed down to these when 
+//!they're opened for writing. All of this can be changed for the whole process with 
+//!`nonvolatile::set_permissions`.
+//!
+//!
+//!# Encryption
+//!
+//!With the `encryption` feature enabled, states can be encrypted at rest using the 
+//!`*_encrypted` functions (`new_encrypted`, `load_encrypted`, `load_else_create_encrypted`, and 
+//!their `_from` variants). The manifest is encrypted with XChaCha20-Poly1305, using either a raw 
+//!256-bit key or a key derived from a passphrase with Argon2id. Loading with the wrong key, 
+//!or loading an encrypted state with plain `load`, returns an error rather than garbage.
+//!
+//!If only a few values are sensitive, individual values can be encrypted instead with 
+//!`set_secret`/`get_secret`, after providing a key (raw, passphrase, or a 0600 keyfile) with 
+//!`set_secret_key`. Secrets are never returned by `get`, and are redacted from `Debug` output.
+//!Copying or moving a state carries its secrets over still encrypted, with the same key.
+//!
 //!
 //!# Available State Functions
 //!
@@ -73,6 +183,8 @@ August 2019
 //! pub fn get<'de, T>          (&self, var: &str)               -> Option<T>
 //! pub fn has                  (&self, item: &str)              -> bool
 //! pub fn delete               (&mut self, name: &str)          -> Result<()>
+//! pub fn import_file          (&mut self, path: &str, format: ImportFormat, mode: MergeMode) -> Result<ImportReport>
+//! pub fn close                (self)                           -> Result<()>
 //!
 //! pub fn load_else_create     (name: &str)                     -> Result<State>
 //! pub fn load_else_create_from(name: &str, storage_path: &str) -> Result<State>
@@ -80,8 +192,21 @@ August 2019
 //! pub fn new_from             (name: &str, storage_path: &str) -> Result<State>
 //! pub fn load                 (name: &str)                     -> Result<State>
 //! pub fn load_from            (name: &str, storage_path: &str) -> Result<State>
-//! pub fn destroy_state        (name: &str)
-//! pub fn destroy_state_from   (name: &str, storage_path: &str)
+//! pub fn destroy_state        (name: &str, force: bool)        -> Result<()>
+//! pub fn destroy_state_from   (name: &str, storage_path: &str, force: bool) -> Result<()>
+//! pub fn force_unlock         (name: &str, force: bool)        -> Result<()>
+//! pub fn force_unlock_from    (name: &str, storage_path: &str, force: bool) -> Result<()>
+//! pub fn load_steal           (name: &str, force: bool)        -> Result<State>
+//! pub fn load_steal_from      (name: &str, storage_path: &str, force: bool) -> Result<State>
+//! pub fn rename               (old: &str, new: &str, overwrite: bool) -> Result<()>
+//! pub fn rename_from          (old: &str, new: &str, storage_path: &str, overwrite: bool) -> Result<()>
+//! pub fn copy                 (name: &str, dest_name: &str, overwrite: bool) -> Result<()>
+//! pub fn copy_from            (name: &str, dest_name: &str, storage_path: &str, overwrite: bool) -> Result<()>
+//! pub fn move_to              (name: &str, from_dir: &str, to_dir: &str, overwrite: bool) -> Result<()>
+//!
+//! pub fn builder              (name: &str)                     -> StateBuilder
+//! pub fn in_memory            (name: &str)                     -> State
+//! pub fn temporary            (name: &str)                     -> Result<State>
 //! ```
 
 #![crate_name = "nonvolatile"]
@@ -93,16 +218,17 @@ use whoami::Platform::{Linux, Windows, MacOS};
 use serde::{Serialize, Deserialize};
 use serde_yaml;
 use std::fs::{
-	create_dir_all, 
 	rename, 
 	metadata,
 	read_to_string, 
-	OpenOptions,
+	read_dir,
 	remove_file,
 	remove_dir_all,
 };
 use std::collections::HashMap;
+use std::borrow::Cow;
 use std::env;
+use std::fmt;
 use std::io::Write;
 use std::process;
 use std::thread;
@@ -110,9 +236,10 @@ use std::time;
 use std::mem::drop;
 use std::vec::Vec;
 use std::convert::Into;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::io::ErrorKind;
 use rand::random;
-use sysinfo::{System, ProcessExt, SystemExt, Pid};
 use generic_error::{Result, GenErr, GenericError};
 use regex::Regex;
 use lazy_static::lazy_static;
@@ -120,8 +247,115 @@ use lazy_static::lazy_static;
 #[cfg(test)]
 mod tests;
 
+mod backend;
+pub use backend::{Backend, FsBackend, MemoryBackend};
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteBackend;
+
+mod builder;
+pub use builder::{StateBuilder, Format};
+
+mod shared;
+pub use shared::SharedState;
+
+mod layered;
+pub use layered::{LayeredState, Layer};
+
+mod import;
+pub use import::{ImportFormat, MergeMode, ImportReport};
+
+mod relocate;
+
+#[cfg(feature = "tokio")]
+mod async_state;
+#[cfg(feature = "tokio")]
+pub use async_state::AsyncState;
+
+mod liveness;
+
+mod blobs;
+
+mod lazy;
+use lazy::{Buffer, LazyItems};
+
+mod editable;
+
+mod lock;
+pub use lock::{LockInfo, lock_info, lock_info_from};
+
+mod permissions;
+pub use permissions::{Permissions, InsecureAction, set_permissions, get_permissions};
+
+#[cfg(feature = "encryption")]
+mod encryption;
+#[cfg(feature = "encryption")]
+pub use encryption::EncryptionKey;
+#[cfg(feature = "encryption")]
+use encryption::{ManifestCipher, SecretCipher};
+
+
+///The version of the manifest layout written by this version of nonvolatile. Version 0 
+///manifests are whole serialized `State`s, including paths and the owner's lock ID.
+const MANIFEST_VERSION: u32 = 1;
+
+
+///What gets written to storage for each state. Everything else about a `State` (where it's 
+///stored, who has it locked) is worked out again when it's loaded.
+#[derive(Serialize, Deserialize)]
+struct Manifest<'a> {
+	name: Cow<'a, str>,
+	#[serde(default)]
+	version: u32,
+	//left out if the backend stores items separately
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	items: Option<Cow<'a, HashMap<String, String>>>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	blobs: Option<Cow<'a, HashMap<String, String>>>,
+	//secrets are always (de)serialized, so that a build without the `encryption` feature 
+	//doesn't silently drop them when it rewrites the manifest
+	#[serde(default)]
+	secrets: Cow<'a, HashMap<String, String>>,
+	#[serde(default)]
+	secret_salt: Option<Cow<'a, str>>,
+}
+
+
+///What a manifest holds, once it's been read back from storage.
+struct Contents {
+	items: HashMap<String, String>,
+	blobs: HashMap<String, String>,
+	secrets: HashMap<String, String>,
+	secret_salt: Option<String>,
+	//the version of the manifest layout it was read from
+	version: u32,
+	//the manifest's text, if it's in the editable format
+	editable: Option<String>,
+}
+
+
+impl Contents {
+
+	fn from_manifest(name: &str, manifest: Manifest<'_>) -> Result<Contents> {
+		if manifest.version > MANIFEST_VERSION {
+			return GenErr!("nonvolatile: state \"{}\" was written by a newer version of nonvolatile", name);
+		}
+		let blobs = manifest.blobs.map(Cow::into_owned).unwrap_or_default();
+		check_blobs(name, &blobs)?;
+		Ok(Contents {
+			items: manifest.items.map(Cow::into_owned).unwrap_or_default(),
+			blobs,
+			secrets: manifest.secrets.into_owned(),
+			secret_salt: manifest.secret_salt.map(Cow::into_owned),
+			version: manifest.version,
+			editable: None,
+		})
+	}
+}
+
 
-#[derive(Serialize, Deserialize, Debug)]
 pub struct State {
 	name: String,
 	path: String,
@@ -130,9 +364,69 @@ pub struct State {
 	manifest_path: String,
 	tmp_manifest_path: String,
 	items: HashMap<String, String>,
+	//values too big to keep in the manifest, by the digest of the blob they're stored in
+	blobs: HashMap<String, String>,
+	secrets: HashMap<String, String>,
+	secret_salt: Option<String>,
+	//the version of the manifest the state was loaded from
+	manifest_version: u32,
+	format: Format,
+	read_only: bool,
+	//set once `close` has released the lock, so that `drop` doesn't do it again
+	released: bool,
+	shared: bool,
+	lease: Option<lock::LeaseRenewer>,
+	backend: Arc<dyn Backend>,
+	temp_dir: Option<String>,
+	blob_threshold: Option<usize>,
+	//with `StateBuilder::lazy`, the items that haven't been needed yet. Everything is moved 
+	//into `items` before the first change.
+	lazy: Option<lazy::LazyItems>,
+	//the editable manifest as it was last read, so hand-written comments can be kept
+	editable: Option<String>,
+	#[cfg(feature = "encryption")]
+	cipher: Option<ManifestCipher>,
+	#[cfg(feature = "encryption")]
+	secret_cipher: Option<SecretCipher>,
+}
+
+
+impl fmt::Debug for State {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let secrets: HashMap<&String, &str> = self.secrets.keys().map(|k| (k, "<redacted>")).collect();
+		f.debug_struct("State")
+			.field("name", &self.name)
+			.field("path", &self.path)
+			.field("identifier", &self.identifier)
+			.field("lockfile_path", &self.lockfile_path)
+			.field("manifest_path", &self.manifest_path)
+			.field("tmp_manifest_path", &self.tmp_manifest_path)
+			.field("items", &self.items)
+			.field("blobs", &self.blobs)
+			.field("lazy", &self.lazy)
+			.field("secrets", &secrets)
+			.field("format", &self.format)
+			.field("read_only", &self.read_only)
+			.field("shared", &self.shared)
+			.field("backend", &self.backend)
+			.finish()
+	}
 }
 
 
+const LOCKFILE_NAME: &str = "~rust_nonvolatile.lock";
+//in shared mode, every process that has the state open registers itself with one of these
+const SHARED_LOCKFILE_PREFIX: &str = "~rust_nonvolatile.shared.";
+//held by a process in shared mode for the duration of a single write
+const WRITE_LOCKFILE_NAME: &str = "~rust_nonvolatile.write.lock";
+const WRITE_LOCK_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+
+///Every encrypted manifest starts with this, so that plain loads can tell the user what's wrong
+///instead of failing to parse binary data.
+pub(crate) const ENCRYPTED_MAGIC: &[u8] = b"NVENC1\n";
+
+
 enum WhoOwns {
 	Me,
 	Other,
@@ -185,71 +479,166 @@ fn canonicalize_path<S>(path: S) -> String
 }
 
 
-fn build_var_path(var: &str, sub_dir: &str) -> Result<String> {
-	let s = match env::var(var) {
-		Ok(s) => s,
-		Err(_) => match whoami::platform() {	//An error here indicates that either $HOME or %appdata% is not defined
-			Windows => String::from("C:/ProgramData"),
-			_ => String::from("/etc")
-		}
-	};
-	Ok(format!("{}/{}", s, sub_dir))
+///Which kind of data a state holds. On Linux this decides which XDG base directory the state
+///is stored under by default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StateKind {
+	///Settings and configuration, under `$XDG_CONFIG_HOME` (`~/.config`). Used by `new`, `load`, etc.
+	Config,
+	///Persistent data that isn't configuration, under `$XDG_DATA_HOME` (`~/.local/share`).
+	Data,
+	///Things like history or recently used files, under `$XDG_STATE_HOME` (`~/.local/state`).
+	State,
 }
 
 
-fn get_storage_dir() -> Result<String> {
-	match whoami::platform() {
+const STORAGE_SUB_DIR: &str = "rust_nonvolatile";
+
+
+#[cfg(unix)]
+fn is_root() -> bool {
+	unsafe { libc::geteuid() == 0 }
+}
+
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+	false
+}
+
+
+///Work out the default storage directory. `var` looks up environment variables, so that
+///this can be tested without touching the real environment.
+fn resolve_storage_dir<F>(kind: StateKind, platform: whoami::Platform, var: F) -> Result<String>
+	where F: Fn(&str) -> Option<String>
+{
+	//empty variables count as unset, per the XDG spec
+	let var = |name: &str| var(name).filter(|value| !value.is_empty());
+	if let Some(dir) = var("NONVOLATILE_DIR") {
+		return Ok(dir);
+	}
+	match platform {
 		Linux => {
-			build_var_path("HOME", ".local/rust_nonvolatile")
+			let (xdg_var, home_sub_dir) = match kind {
+				StateKind::Config => ("XDG_CONFIG_HOME", ".config"),
+				StateKind::Data => ("XDG_DATA_HOME", ".local/share"),
+				StateKind::State => ("XDG_STATE_HOME", ".local/state"),
+			};
+			//relative paths in XDG variables are invalid, and should be ignored
+			if let Some(dir) = var(xdg_var).filter(|dir| dir.starts_with('/')) {
+				return Ok(format!("{}/{}", dir, STORAGE_SUB_DIR));
+			}
+			match var("HOME") {
+				Some(home) => Ok(format!("{}/{}/{}", home, home_sub_dir, STORAGE_SUB_DIR)),
+				None if is_root() => Ok(format!("/etc/{}", STORAGE_SUB_DIR)),
+				None => GenErr!("nonvolatile: cannot find a storage directory; set $HOME or $NONVOLATILE_DIR"),
+			}
 		},
 		Windows => {
-			build_var_path("appdata", "rust_nonvolatile")
+			match var("appdata") {
+				Some(appdata) => Ok(format!("{}/{}", appdata, STORAGE_SUB_DIR)),
+				None => Ok(format!("C:/ProgramData/{}", STORAGE_SUB_DIR)),
+			}
 		},
 		MacOS => {
-			build_var_path("HOME", ".local/rust_nonvolatile")
-		}
-		_ => GenErr!("nonvolatile: {} not supported", whoami::platform()),
+			match var("HOME") {
+				Some(home) => Ok(format!("{}/.local/{}", home, STORAGE_SUB_DIR)),
+				None if is_root() => Ok(format!("/etc/{}", STORAGE_SUB_DIR)),
+				None => GenErr!("nonvolatile: cannot find a storage directory; set $HOME or $NONVOLATILE_DIR"),
+			}
+		},
+		_ => GenErr!("nonvolatile: {} not supported", platform),
 	}
 }
 
 
-fn get_state_id() -> Result<String> {
-	let this_pid = process::id();
-	let mut system = System::new();
-	system.refresh_processes();
-	let this_proc = match system.get_processes().get(&(this_pid as Pid)) {
-		Some(process) => process,
-		None => return GenErr!("nonvolatile internal error: my pid should be {} but no process is listed at that PID", this_pid)
-	};
-	let exe_path = this_proc.exe().to_string_lossy().to_string();
-	Ok(format!("{}\n{}\n{}", process::id(), random::<u32>(), exe_path))
+///Get the directory states of the given kind are stored in by default.
+///
+///If `$NONVOLATILE_DIR` is set, it is always used. Otherwise, on Linux this is 
+///`$XDG_CONFIG_HOME`, `$XDG_DATA_HOME` or `$XDG_STATE_HOME` (depending on `kind`) followed by 
+///`/rust_nonvolatile`, falling back to the XDG defaults under `$HOME`. MacOS uses 
+///`$HOME/.local/rust_nonvolatile`, and Windows uses `%appdata%\rust_nonvolatile`.
+///
+///### Example
+///
+///```rust
+///let dir = nonvolatile::storage_dir(StateKind::Data)?;
+///let state = State::load_else_create_from("my_cache", &dir)?;
+///```
+pub fn storage_dir(kind: StateKind) -> Result<String> {
+	resolve_storage_dir(kind, whoami::platform(), |name| env::var(name).ok())
 }
 
 
-fn match_state_id(my_id: &str, read_id: &str) -> WhoOwns {
-	if my_id == read_id {
-		return WhoOwns::Me;
+fn get_storage_dir() -> Result<String> {
+	storage_dir(StateKind::Config)
+}
+
+
+///Where states were stored on Linux before nonvolatile followed the XDG spec.
+fn legacy_storage_dir() -> Option<String> {
+	match whoami::platform() {
+		Linux => env::var("HOME").ok().map(|home| format!("{}/.local/{}", home, STORAGE_SUB_DIR)),
+		_ => None,
 	}
-	let parts: Vec<&str> = read_id.split("\n").collect();
-	let parts = match parts.len() {
-		3 => (parts[0], parts[1], parts[2]),
-		_ => return WhoOwns::Nobody,
-	};
-	let read_pid: u32 = match parts.0.parse() {
-		Ok(pid) => pid,
-		Err(_) => return WhoOwns::Nobody,
+}
+
+
+///If the named state doesn't exist in `storage_dir` but does exist in the legacy storage 
+///directory, move it over. Fails if the legacy state is currently open by someone else.
+fn migrate_legacy_state(name: &str, storage_dir: &str) -> Result<()> {
+	let legacy_dir = match legacy_storage_dir() {
+		Some(dir) => dir,
+		None => return Ok(()),
 	};
+	let legacy_path = canonicalize_path(format!("{}/{}", legacy_dir, name));
+	let path = canonicalize_path(format!("{}/{}", storage_dir, name));
+	if legacy_path == path || metadata(&path).is_ok() || metadata(format!("{}/.manifest", legacy_path)).is_err() {
+		return Ok(());
+	}
 	
-	let mut system = System::new();
-	system.refresh_processes();
+	let lockfile_path = format!("{}/{}", legacy_path, LOCKFILE_NAME);
+	if let Err(e) = get_lock_acquired(&lockfile_path, "") {
+		return GenErr!("nonvolatile: cannot migrate state \"{}\" from {}: {}", name, legacy_dir, e);
+	}
+	let _ = remove_file(&lockfile_path);
 	
-	for (other_pid, process) in system.get_processes() {
-		let exe_path = process.exe().to_string_lossy().to_string();
-		if *other_pid as u32 == read_pid && parts.2 == &exe_path {
-			return WhoOwns::Other;
-		}
+	permissions::create_dir(storage_dir)?;
+	if rename(&legacy_path, &path).is_err() {
+		//probably on different filesystems
+		permissions::create_dir(&path)?;
+		fs_util::copy_dir(&legacy_path, &path)?;
+		remove_dir_all(&legacy_path)?;
+	}
+	Ok(())
+}
+
+
+///The ID written to lockfiles: PID, a random number to tell states in the same process
+///apart, executable path, process start time (where available) to guard against PID reuse,
+///when the lock was acquired, hostname, and boot ID. See `LockInfo`.
+fn get_state_id() -> Result<String> {
+	Ok(lock::format_lock_id(process::id(), &liveness::current_exe(), liveness::current_start_time()))
+}
+
+
+fn match_state_id(my_id: &str, read_id: &str) -> WhoOwns {
+	if lock::same_owner(my_id, read_id) {
+		return WhoOwns::Me;
+	}
+	match LockInfo::parse(read_id) {
+		Some(info) if info.is_alive() => WhoOwns::Other,
+		_ => WhoOwns::Nobody,
+	}
+}
+
+
+///Describe the owner of a lock for error messages.
+fn describe_owner(read_id: &str) -> String {
+	match LockInfo::parse(read_id) {
+		Some(info) => info.to_string(),
+		None => String::from(read_id),
 	}
-	WhoOwns::Nobody
 }
 
 
@@ -263,10 +652,37 @@ fn get_lock_acquired(lockfile_path: &str, state_id: &str) -> Result<bool> {
 	}
 	let read_id = read_to_string(lockfile_path)?;
 	match match_state_id(state_id, &read_id) {
-		WhoOwns::Me => return Ok(true),
-		WhoOwns::Other => return GenErr!("lockfile {} already owned by state {}", lockfile_path, read_id),
-		WhoOwns::Nobody => return Ok(false),
+		WhoOwns::Me => Ok(true),
+		WhoOwns::Other => GenErr!("lockfile {} already owned by {}", lockfile_path, describe_owner(&read_id)),
+		WhoOwns::Nobody => Ok(false),
+	}
+}
+
+
+///Fail if any process still has the state in `dir` open in shared mode, and clean up
+///after any that have exited without unregistering.
+fn check_shared_holders(dir: &Path, state_id: &str) -> Result<()> {
+	let entries = match read_dir(dir) {
+		Ok(entries) => entries,
+		Err(_) => return Ok(()),
+	};
+	for entry in entries.flatten() {
+		if !entry.file_name().to_string_lossy().starts_with(SHARED_LOCKFILE_PREFIX) {
+			continue;
+		}
+		let read_id = match read_to_string(entry.path()) {
+			Ok(read_id) => read_id,
+			Err(_) => continue,
+		};
+		match match_state_id(state_id, &read_id) {
+			WhoOwns::Me => (),
+			WhoOwns::Other => return GenErr!("state {} is open in shared mode by {}", dir.display(), describe_owner(&read_id)),
+			WhoOwns::Nobody => {
+				let _ = remove_file(entry.path());
+			},
+		}
 	}
+	Ok(())
 }
 
 
@@ -278,9 +694,12 @@ fn acquire_dir(lockfile_path: &str, state_id: &str) -> Result<()> {
 			return Err(e)
 		},
 	};
+	if let Some(dir) = Path::new(lockfile_path).parent() {
+		check_shared_holders(dir, state_id)?;
+	}
 	
 	let _ = remove_file(lockfile_path);
-	let mut file = OpenOptions::new().write(true).create(true).open(lockfile_path)?;
+	let mut file = permissions::create_file(lockfile_path)?;
 	match write!(file, "{}", state_id) {
 		Ok(_) => (),
 		Err(e) => {
@@ -299,24 +718,336 @@ fn acquire_dir(lockfile_path: &str, state_id: &str) -> Result<()> {
 }
 
 
+///Open the state in `path` in shared mode, registering with the file at `lockfile_path`. 
+///Fails if someone has it open exclusively.
+fn register_shared(path: &str, lockfile_path: &str, state_id: &str) -> Result<()> {
+	get_lock_acquired(&format!("{}/{}", path, LOCKFILE_NAME), state_id)?;
+	let mut file = permissions::create_file(lockfile_path)?;
+	if let Err(e) = write!(file, "{}", state_id) {
+		let _ = remove_file(lockfile_path);
+		return Err(e.into());
+	}
+	Ok(())
+}
+
+
+///Make sure every blob a manifest refers to is named by a digest, so that a hand-edited or 
+///corrupted manifest can't point outside the blob directory.
+fn check_blobs(name: &str, blobs: &HashMap<String, String>) -> Result<()> {
+	match blobs.iter().find(|(_, digest)| !blobs::is_digest(digest)) {
+		Some((key, digest)) => GenErr!("nonvolatile: manifest of state \"{}\" is invalid: \"{}\" refers to blob \"{}\", which isn't a digest", name, key, digest),
+		None => Ok(()),
+	}
+}
+
+
+///Parse a manifest, in whichever format it was written.
+fn parse_manifest(name: &str, data: &[u8]) -> Result<Contents> {
+	if !editable::is_editable(data) {
+		return Contents::from_manifest(name, serde_yaml::from_slice(data)?);
+	}
+	let text = String::from_utf8_lossy(data).to_string();
+	let (items, meta) = match editable::parse(&text) {
+		Ok(parsed) => parsed,
+		Err(e) => return GenErr!("nonvolatile: manifest of state \"{}\" is invalid: {}", name, e),
+	};
+	check_blobs(name, &meta.blobs)?;
+	Ok(Contents {
+		items,
+		blobs: meta.blobs,
+		secrets: meta.secrets,
+		secret_salt: meta.secret_salt,
+		version: MANIFEST_VERSION,
+		editable: Some(text),
+	})
+}
+
+
+///Write out a manifest made by `State::encode_manifest`, along with the state's items if the
+///backend stores them separately.
+fn write_encoded(backend: &dyn Backend, name: &str, data: &[u8], items: Option<&HashMap<String, String>>) -> Result<()> {
+	backend.write_manifest(name, data)?;
+	match items {
+		Some(items) => backend.replace_items(name, items),
+		None => Ok(()),
+	}
+}
+
+
+///Take the short-lived lock that serializes writes in shared mode.
+fn acquire_write_lock(write_lock_path: &str, state_id: &str) -> Result<()> {
+	let start = time::Instant::now();
+	loop {
+		match permissions::create_new_file(write_lock_path) {
+			Ok(mut file) => {
+				if let Err(e) = write!(file, "{}", state_id) {
+					let _ = remove_file(write_lock_path);
+					return Err(e.into());
+				}
+				return Ok(());
+			},
+			Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+			Err(e) => return Err(e.into()),
+		}
+		
+		//if the writer died mid-write, the lock will never be released. An empty lockfile 
+		//might just mean the writer hasn't written its ID yet, so give it a moment.
+		let read_id = read_to_string(write_lock_path).unwrap_or_default();
+		let age = metadata(write_lock_path).and_then(|m| m.modified()).ok()
+			.and_then(|modified| modified.elapsed().ok())
+			.unwrap_or_default();
+		let stale = match read_id.is_empty() {
+			true => age > time::Duration::from_secs(1),
+			false => matches!(match_state_id(state_id, &read_id), WhoOwns::Nobody),
+		};
+		if stale {
+			let _ = remove_file(write_lock_path);
+			continue;
+		}
+		if start.elapsed() > WRITE_LOCK_TIMEOUT {
+			return GenErr!("nonvolatile: timed out waiting for write lock {} (held by {})", write_lock_path, describe_owner(&read_id));
+		}
+		thread::sleep(time::Duration::from_millis(1));
+	}
+}
+
+
 impl State {
 
-	fn write_manifest(&self) -> Result<()> {
-		let mut file = OpenOptions::new().write(true).create(true).open(&self.tmp_manifest_path)?;
-		let data = serde_yaml::to_vec(self)?;
-		file.write(&data)?;
-		rename(&self.tmp_manifest_path, &self.manifest_path)?;
+	///A new, empty state that isn't stored anywhere yet.
+	fn blank(name: &str, backend: Arc<dyn Backend>) -> State {
+		State {
+			name: String::from(name),
+			path: String::new(),
+			identifier: String::new(),
+			lockfile_path: String::new(),
+			manifest_path: String::new(),
+			tmp_manifest_path: String::new(),
+			items: HashMap::new(),
+			blobs: HashMap::new(),
+			secrets: HashMap::new(),
+			secret_salt: None,
+			manifest_version: MANIFEST_VERSION,
+			format: Format::default(),
+			read_only: false,
+			released: false,
+			shared: false,
+			lease: None,
+			backend,
+			temp_dir: None,
+			blob_threshold: None,
+			lazy: None,
+			editable: None,
+			#[cfg(feature = "encryption")]
+			cipher: None,
+			#[cfg(feature = "encryption")]
+			secret_cipher: None,
+		}
+	}
+	
+	
+	///Replace everything stored in the state with what was read back from storage.
+	fn replace_contents(&mut self, contents: Contents) {
+		self.lazy = None;
+		self.items = contents.items;
+		self.blobs = contents.blobs;
+		self.secrets = contents.secrets;
+		self.secret_salt = contents.secret_salt;
+		self.manifest_version = contents.version;
+		self.editable = contents.editable;
+	}
+	
+	
+	///The manifest to write out for this state.
+	fn to_manifest(&self) -> Manifest<'_> {
+		Manifest {
+			name: Cow::Borrowed(&self.name),
+			version: MANIFEST_VERSION,
+			items: match self.stores_items() {
+				true => None,
+				false => Some(Cow::Borrowed(&self.items)),
+			},
+			blobs: match self.blobs.is_empty() {
+				true => None,
+				false => Some(Cow::Borrowed(&self.blobs)),
+			},
+			secrets: Cow::Borrowed(&self.secrets),
+			secret_salt: self.secret_salt.as_deref().map(Cow::Borrowed),
+		}
+	}
+	
+	
+	fn check_writable(&self) -> Result<()> {
+		if self.read_only {
+			return GenErr!("nonvolatile: state \"{}\" was opened read-only", self.name);
+		}
+		if let Some(lease) = &self.lease {
+			lease.check()?;
+		}
 		Ok(())
 	}
 	
 	
+	///Read the manifest back from storage, using the same key this state was opened with.
+	fn read_manifest(&self) -> Result<Contents> {
+		let data = match self.backend.read_manifest(&self.name)? {
+			Some(data) => data,
+			None => return GenErr!("nonvolatile: state \"{}\" no longer exists", self.name),
+		};
+		#[cfg(feature = "encryption")]
+		let data = match &self.cipher {
+			Some(cipher) => cipher.unseal(&data)?,
+			None => data,
+		};
+		if data.starts_with(ENCRYPTED_MAGIC) {
+			return GenErr!("nonvolatile: state \"{}\" has been encrypted by someone else", self.name);
+		}
+		let mut contents = parse_manifest(&self.name, &data)?;
+		if self.stores_items() {
+			contents.items = self.backend.read_items(&self.name)?;
+		}
+		Ok(contents)
+	}
+	
+	
+	///Whether the items are stored separately from the manifest. See `Backend::stores_items`.
+	fn stores_items(&self) -> bool {
+		if self.format == Format::Editable {
+			return false;
+		}
+		#[cfg(feature = "encryption")]
+		{
+			if self.cipher.is_some() {
+				return false;
+			}
+		}
+		self.backend.stores_items()
+	}
+	
+	
+	///Apply `change` to the state and write it out. If `change` only affects the item (or 
+	///secret) called `key`, and the backend stores items separately, only that item is written.
+	///
+	///In shared mode, this happens under the write lock, and `change` is applied on top of 
+	///whatever is in storage at the time, so changes made by other processes aren't lost.
+	fn commit<F>(&mut self, key: Option<&str>, change: F) -> Result<()> where F: FnOnce(&mut State) {
+		self.check_writable()?;
+		self.load_items();
+		if !self.shared {
+			let secret = key.and_then(|key| self.secrets.get(key).cloned());
+			let blob = key.and_then(|key| self.blobs.get(key).cloned());
+			change(self);
+			match key {
+				Some(key) if self.stores_items() => {
+					if self.secrets.get(key) != secret.as_ref() || self.blobs.get(key) != blob.as_ref() {
+						self.backend.write_manifest(&self.name, &self.encode_manifest()?)?;
+					}
+					self.backend.write_items(&self.name, &[(key, self.items.get(key).map(String::as_str))])?;
+				},
+				_ => self.write_manifest()?,
+			};
+			if let Some(blob) = blob {
+				self.release_blob(&blob);
+			}
+			return Ok(());
+		}
+		
+		let write_lock_path = format!("{}/{}", self.path, WRITE_LOCKFILE_NAME);
+		acquire_write_lock(&write_lock_path, &self.identifier)?;
+		let result = self.read_manifest().and_then(|fresh| {
+			self.items = fresh.items;
+			self.blobs = fresh.blobs;
+			self.secrets = fresh.secrets;
+			if fresh.secret_salt.is_some() {
+				self.secret_salt = fresh.secret_salt;
+			}
+			let blob = key.and_then(|key| self.blobs.get(key).cloned());
+			change(self);
+			self.write_manifest()?;
+			if let Some(blob) = blob {
+				self.release_blob(&blob);
+			}
+			Ok(())
+		});
+		let _ = remove_file(&write_lock_path);
+		result
+	}
+	
+	
+	///Decode any items that haven't been used yet (see `StateBuilder::lazy`), so that `items` 
+	///holds all of them.
+	pub(crate) fn load_items(&mut self) {
+		if let Some(lazy) = self.lazy.take() {
+			self.items = lazy.into_items();
+		}
+	}
+	
+	
+	///Remove the blob with the given digest if no item refers to it anymore. If that fails, 
+	///it's collected the next time the state is loaded.
+	fn release_blob(&self, digest: &str) {
+		if !self.blobs.values().any(|d| d == digest) {
+			let _ = blobs::remove_blob(&self.path, digest);
+		}
+	}
+	
+	
+	///Read every value stored in a blob back into `items`, for users of the state that only 
+	///look at `items`. The blobs are collected the next time the state is loaded.
+	pub(crate) fn inline_blobs(&mut self) {
+		let State { items, blobs, path, .. } = self;
+		blobs.retain(|key, digest| match blobs::read_blob(path, digest) {
+			Ok(value) => {
+				let _ = items.insert(key.clone(), value);
+				false
+			},
+			Err(_) => true,
+		});
+	}
+	
+	
+	///Serialize (and if needed, encrypt) the manifest, ready to be written out.
+	fn encode_manifest(&self) -> Result<Vec<u8>> {
+		let data = match self.format {
+			Format::Editable => {
+				let meta = editable::Meta {
+					secrets: self.secrets.clone(),
+					secret_salt: self.secret_salt.clone(),
+					blobs: self.blobs.clone(),
+				};
+				editable::render(self.editable.as_deref(), &self.items, &meta)?.into_bytes()
+			},
+			Format::Yaml => serde_yaml::to_vec(&self.to_manifest())?,
+			Format::Json => serde_json::to_vec_pretty(&self.to_manifest())?,
+		};
+		#[cfg(feature = "encryption")]
+		let data = match &self.cipher {
+			Some(cipher) => cipher.seal(&data)?,
+			None => data,
+		};
+		Ok(data)
+	}
+	
+	
+	fn write_manifest(&self) -> Result<()> {
+		self.check_writable()?;
+		let data = self.encode_manifest()?;
+		let items = match self.stores_items() {
+			true => Some(&self.items),
+			false => None,
+		};
+		write_encoded(&*self.backend, &self.name, &data, items)
+	}
+	
+	
 	///Set a variable with name `var` and value `value`. 
 	///
 	///The name of the set value must be distinct from any other values you set,
 	///but otherwise no restrictions apply. The type of `value` must be serializable, 
 	///but no other restrictions apply. 
 	///
-	///The value is written out to storage immediately.
+	///The value is written out to storage immediately. If it's bigger than the state's 
+	///`StateBuilder::blob_threshold`, it's written to a file of its own instead of the manifest.
 	///
 	///### Example
 	///
@@ -329,18 +1060,45 @@ impl State {
 	///state.set("some_other_var", some_other_var.clone()) //save the map for later!
 	///```
 	pub fn set<T>(&mut self, var: &str, value: T) -> Result<()> where T: Serialize {
-		let _ = self.items.insert(String::from(var), serde_yaml::to_string(&value)?);
-		self.write_manifest()
+		self.set_serialized(var, serde_yaml::to_string(&value)?)
+	}
+	
+	
+	///Set `var` to a value that's already been serialized. See `set`.
+	fn set_serialized(&mut self, var: &str, value: String) -> Result<()> {
+		self.check_writable()?;
+		if self.format == Format::Editable {
+			editable::check_key(var)?;
+		}
+		let blob = match self.blob_threshold {
+			Some(threshold) if value.len() > threshold => Some(blobs::write_blob(&self.path, &value)?),
+			_ => None,
+		};
+		self.commit(Some(var), |state| {
+			match blob {
+				Some(blob) => {
+					let _ = state.items.remove(var);
+					let _ = state.blobs.insert(String::from(var), blob);
+				},
+				None => {
+					let _ = state.items.insert(String::from(var), value);
+					let _ = state.blobs.remove(var);
+				},
+			}
+			let _ = state.secrets.remove(var);
+		})
 	}
 	
 
 	///Try to retrieve a variable that was previously written to storage. 
 	///
 	///The return will be the value if it can be found, or None if:
-	/// * no value with that name is stored, or
-	/// * the stored value had a type incompatible with the `get` call.
+	/// * no value with that name is stored, 
+	/// * the stored value had a type incompatible with the `get` call, or
+	/// * the value was stored with `set_secret` (use `get_secret` instead).
 	///
-	///`get` does not modify or remove the stored value, it only reads it.
+	///`get` does not modify or remove the stored value, it only reads it. Values stored in a 
+	///file of their own (see `StateBuilder::blob_threshold`) are read from it on each `get`.
 	///
 	///### Example
 	///
@@ -350,7 +1108,18 @@ impl State {
 	///let some_other_var = state.get::<HashMap<u64, String>>("some_other_var");
 	///```
 	pub fn get<'de, T>(&self, var: &str) -> Option<T> where for<'a> T: Deserialize<'a> {
-		let item = self.items.get(var)?;
+		let loaded;
+		let item = match (self.blobs.get(var), &self.lazy) {
+			(Some(digest), _) => {
+				loaded = blobs::read_blob(&self.path, digest).ok()?;
+				&loaded
+			},
+			(None, Some(lazy)) => {
+				loaded = lazy.get(var)?;
+				&loaded
+			},
+			(None, None) => self.items.get(var)?,
+		};
 		match serde_yaml::from_str(item) {
 			Ok(obj) => Some(obj),
 			Err(_) => None,
@@ -358,7 +1127,7 @@ impl State {
 	}
 
 
-	///Check if the given item/key exists in the state.
+	///Check if the given item/key exists in the state. Secrets count as existing items.
 	///
 	///### Example
 	///
@@ -369,7 +1138,30 @@ impl State {
 	///println!("{}", state.has("user_wants_to_die")); // true
 	///```
 	pub fn has(&self, item: &str) -> bool {
-		self.items.contains_key(item)
+		self.items.contains_key(item) 
+			|| self.lazy.as_ref().map(|lazy| lazy.contains_key(item)) == Some(true)
+			|| self.blobs.contains_key(item) 
+			|| self.secrets.contains_key(item)
+	}
+	
+	
+	///Re-read the state from storage, discarding the in-memory copy. 
+	///
+	///This is useful in shared mode (see `StateBuilder::shared`), where other processes may 
+	///have changed the state since it was loaded, and for editable manifests (see 
+	///`Format::Editable`), which may have been changed by hand. If the manifest can't be 
+	///parsed, the error says which line is wrong, and the in-memory copy is kept.
+	///
+	///### Example
+	///
+	///```rust
+	///state.reload()?;
+	///let theme: Option<String> = state.get("theme");
+	///```
+	pub fn reload(&mut self) -> Result<()> {
+		let fresh = self.read_manifest()?;
+		self.replace_contents(fresh);
+		Ok(())
 	}
 	
 	
@@ -385,11 +1177,176 @@ impl State {
 	///state.delete("my var");
 	///```
 	pub fn delete(&mut self, name: &str) -> Result<()> {
-		let _ = self.items.remove(name);
-		self.write_manifest()
+		self.commit(Some(name), |state| {
+			let _ = state.items.remove(name);
+			let _ = state.blobs.remove(name);
+			let _ = state.secrets.remove(name);
+		})
+	}
+	
+	
+	///Import the settings in a TOML, JSON, INI or .env file, all in one write. Nested tables 
+	///(and INI sections) become dotted keys, so `port` in the table `server` is imported as 
+	///`server.port`. Arrays are imported as they are. Values in INI and .env files are read as 
+	///numbers or booleans where they look like one, and as strings otherwise.
+	///
+	///Keys that are already in the state with a different value are conflicts, which are 
+	///handled according to `mode` and listed in the returned report.
+	///
+	///### Example
+	///
+	///```rust
+	///let report = state.import_file("config.toml", ImportFormat::Toml, MergeMode::KeepExisting)?;
+	///for key in report.conflicts {
+	///    println!("kept the existing value of {}", key);
+	///}
+	///let port: Option<u16> = state.get("server.port");
+	///```
+	pub fn import_file(&mut self, path: &str, format: ImportFormat, mode: MergeMode) -> Result<ImportReport> {
+		self.check_writable()?;
+		self.load_items();
+		let mut report = ImportReport::default();
+		let mut imports = Vec::new();
+		for (key, value) in import::read_file(path, format)? {
+			let value = serde_yaml::to_string(&value)?;
+			let existing = match self.blobs.get(&key) {
+				Some(digest) => blobs::read_blob(&self.path, digest).ok(),
+				None => self.items.get(&key).cloned(),
+			};
+			if self.secrets.contains_key(&key) || existing.map(|existing| existing != value) == Some(true) {
+				report.conflicts.push(key.clone());
+				if mode != MergeMode::Overwrite {
+					continue;
+				}
+			}
+			report.imported.push(key.clone());
+			imports.push((key, value));
+		}
+		if mode == MergeMode::Fail && !report.conflicts.is_empty() {
+			return GenErr!("nonvolatile: cannot import {}, it conflicts with existing keys: {}", path, report.conflicts.join(", "));
+		}
+		self.commit(None, |state| {
+			for (key, value) in imports {
+				let _ = state.blobs.remove(&key);
+				let _ = state.secrets.remove(&key);
+				let _ = state.items.insert(key, value);
+			}
+		})?;
+		Ok(report)
 	}
 
 
+	///Close the state: wait for everything written to it to reach the disk, and release its
+	///lock. Unlike dropping the state, which does the same on a best-effort basis, this 
+	///returns any error along the way, e.g. if the lockfile couldn't be removed.
+	///
+	///### Example
+	///
+	///```rust
+	///let mut state = State::load_else_create("my_state")?;
+	///state.set("last_run", now)?;
+	///state.close()?;
+	///```
+	pub fn close(mut self) -> Result<()> {
+		if self.read_only || self.released {
+			return Ok(());
+		}
+		let synced = match self.temp_dir {
+			Some(_) => Ok(()),
+			None => self.backend.sync(&self.name),
+		};
+		let _ = self.lease.take();
+		let released = self.backend.release_lock(&self.name, &self.identifier);
+		let removed = match self.temp_dir.take() {
+			Some(temp_dir) => remove_dir_all(temp_dir).map_err(GenericError::from),
+			None => Ok(()),
+		};
+		//the lock is gone, so there's nothing left for `drop` to do
+		self.released = true;
+		synced.and(released).and(removed)
+	}
+	
+	
+	///Start building a `State` with the given name, for when the defaults used by 
+	///`new`, `load`, etc. aren't what you need. See `StateBuilder` for the available options.
+	///
+	///### Example
+	///
+	///```rust
+	///let state = State::builder("my_state")
+	///    .create_if_missing(true)
+	///    .lock_timeout(Duration::from_secs(5))
+	///    .open()?;
+	///```
+	pub fn builder(name: &str) -> StateBuilder {
+		StateBuilder::new(name)
+	}
+	
+	
+	///Turn this state into a `SharedState`, which can be cloned and used from several 
+	///threads at once.
+	///
+	///### Example
+	///
+	///```rust
+	///let state = State::load_else_create("my_state")?.into_shared();
+	///let for_worker = state.clone();
+	///```
+	pub fn into_shared(mut self) -> SharedState {
+		self.load_items();
+		self.inline_blobs();
+		SharedState::new(self)
+	}
+	
+	
+	///Create a new, empty state that only exists in memory. Nothing is ever written to (or 
+	///read from) disk, and no lock is taken, so any number of in-memory states with the same 
+	///name can exist at once. Useful for tests. To reopen in-memory states, use a 
+	///`MemoryBackend` instead.
+	///
+	///### Example
+	///
+	///```rust
+	///let mut state = State::in_memory("my_state");
+	///state.set("var", 17)?;
+	///assert_eq!(state.get::<u32>("var"), Some(17));
+	///```
+	pub fn in_memory(name: &str) -> State {
+		let state = State::blank(name, Arc::new(MemoryBackend::new()));
+		//writing to memory can't fail
+		let _ = state.write_manifest();
+		state
+	}
+	
+	
+	///Create a new, empty state in a fresh temporary directory, which is deleted (along with 
+	///the state) when the state is dropped. Unlike `State::in_memory`, this behaves exactly 
+	///like a state in the usual storage location, but can't clash with any other state. 
+	///Useful for tests.
+	///
+	///### Example
+	///
+	///```rust
+	///let mut state = State::temporary("my_state")?;
+	///state.set("var", 17)?;
+	///```
+	pub fn temporary(name: &str) -> Result<State> {
+		let dir = env::temp_dir().join(format!("rust_nonvolatile.{}.{:08x}", process::id(), random::<u32>()));
+		let dir = dir.to_string_lossy().to_string();
+		permissions::create_dir(&dir)?;
+		match State::builder(name).storage_dir(&dir).overwrite(true).open() {
+			Ok(mut state) => {
+				state.temp_dir = Some(dir);
+				Ok(state)
+			},
+			Err(e) => {
+				let _ = remove_dir_all(&dir);
+				Err(e)
+			},
+		}
+	}
+	
+	
 	///Load state of the given name if it exists. If not, create new state and return that.
 	///
 	///The name must obey naming rules for your filesystem. To simplify cross platform
@@ -403,7 +1360,7 @@ impl State {
 	///state.set("my var", &my_var);
 	///```
 	pub fn load_else_create(name: &str) -> Result<State> {
-		State::load(name).or_else(|_| State::new(name))
+		State::builder(name).create_if_missing(true).open()
 	}
 	
 	
@@ -426,7 +1383,7 @@ impl State {
 	///state.set("my var", &my_var);
 	///```
 	pub fn load_else_create_from(name: &str, storage_path: &str) -> Result<State> {
-		State::load_from(name, storage_path).or_else(|_| State::new_from(name, storage_path))
+		State::builder(name).storage_dir(storage_path).create_if_missing(true).open()
 	}
 
 
@@ -447,8 +1404,7 @@ impl State {
 	///state.set("my var", my_var);
 	///```
 	pub fn new(name: &str) -> Result<State> {
-		let dir = get_storage_dir()?;
-		State::new_from(name, &dir)
+		State::builder(name).overwrite(true).open()
 	}
 	
 
@@ -473,39 +1429,12 @@ impl State {
 	///state.set("my var", my_var);
 	///```
 	pub fn new_from(name: &str, storage_path: &str) -> Result<State> {
-		check_path_valid(name)?;
-		let path = canonicalize_path(format!("{}/{}", storage_path, name));
-		create_dir_all(&path)?;
-		
-		let items: HashMap<String, String> = HashMap::new();
-		
-		let state_id = match get_state_id() {
-			Ok(id) => id,
-			Err(e) => return Err(e.into())
-		};
-		let lockfile_path = format!("{}/{}", &path, "~rust_nonvolatile.lock");
-		acquire_dir(&lockfile_path, &state_id)?;
-		
-		let state = State {
-			name: String::from(name),
-			path: path.clone(),
-			identifier: state_id,
-			lockfile_path: lockfile_path.clone(),
-			manifest_path: format!("{}/{}", &path, ".manifest"),
-			tmp_manifest_path: format!("{}/{}", &path, ".manifest_tmp"),
-			items: items,
-		};
-		
-		match state.write_manifest() {
-			Ok(_) => Ok(state),
-			Err(e) => {
-				let _ = remove_file(&lockfile_path);
-				Err(e.into())
-			}
-		}
+		State::builder(name).storage_dir(storage_path).overwrite(true).open()
 	}
 
 
+
+
 	///Attempt to load state of the given name
 	///
 	///If there is no state with that name, an error will be returned.
@@ -518,8 +1447,7 @@ impl State {
 	///state.set("my var", my_var);
 	///```
 	pub fn load(name: &str) -> Result<State> {
-		let dir = get_storage_dir()?;		
-		State::load_from(name, &dir)
+		State::builder(name).open()
 	}
 	
 	
@@ -535,43 +1463,152 @@ impl State {
 	///state.set("my var", &my_var);
 	///```
 	pub fn load_from(name: &str, storage_path: &str) -> Result<State> {
+		State::builder(name).storage_dir(storage_path).open()
+	}
+
+
+	///Release the lock on the state of the given name, so that it can be loaded again after
+	///its owner hung or crashed.
+	///
+	///Unless `force` is set, this fails if the owner is still running. Forcibly unlocking a
+	///state whose owner is still using it lets two processes write to it at once, so only do
+	///that once you're sure the owner won't write to it again. Processes that have the state
+	///open in shared mode are unregistered in the same way. See `nonvolatile::lock_info`.
+	///
+	///### Example
+	///
+	///```rust
+	/// // the process that had this open is hung; kill it and take over
+	///State::force_unlock("my_state", true)?;
+	///let state = State::load("my_state")?;
+	///```
+	pub fn force_unlock(name: &str, force: bool) -> Result<()> {
+		State::force_unlock_from(name, &get_storage_dir()?, force)
+	}
+
+
+	///Release the lock on the state of the given name in a custom storage location. See `State::force_unlock`.
+	pub fn force_unlock_from(name: &str, storage_path: &str, force: bool) -> Result<()> {
 		check_path_valid(name)?;
 		let path = canonicalize_path(format!("{}/{}", storage_path, name));
-		let manifest_path = format!("{}/{}", &path, ".manifest");
-		
-		let state_id = match get_state_id() {
-			Ok(id) => id,
-			Err(e) => return Err(e.into())
-		};
-		let lockfile_path = format!("{}/{}", &path, "~rust_nonvolatile.lock");
-		
-		acquire_dir(&lockfile_path, &state_id)?;
-		
-		let data = match read_to_string(&manifest_path) {
-			Ok(data) => data,
-			Err(e) => {
-				let _ = remove_file(&lockfile_path);
-				return Err(GenericError::from(e));
+
+		let lockfile_path = format!("{}/{}", path, LOCKFILE_NAME);
+		if let Some(info) = lock::read_lock_info(&lockfile_path)? {
+			if !force && info.is_alive() {
+				return GenErr!("nonvolatile: state \"{}\" is still locked by {}", name, info);
 			}
-		};
-		
-		let mut state: State = match serde_yaml::from_str(&data) {
-			Ok(state) => state,
-			Err(e) => {
-				let _ = remove_file(&lockfile_path);
-				return Err(GenericError::from(e));
+			let _ = remove_file(&lockfile_path);
+		}
+
+		let mut lockfiles = vec![PathBuf::from(format!("{}/{}", path, WRITE_LOCKFILE_NAME))];
+		if let Ok(entries) = read_dir(&path) {
+			for entry in entries.flatten() {
+				if entry.file_name().to_string_lossy().starts_with(SHARED_LOCKFILE_PREFIX) {
+					lockfiles.push(entry.path());
+				}
 			}
-		};
-		
-		state.identifier = state_id;
-		state.lockfile_path = lockfile_path;
-		
-		Ok(state)
+		}
+		for lockfile in lockfiles {
+			let read_id = match read_to_string(&lockfile) {
+				Ok(read_id) => read_id,
+				Err(_) => continue,
+			};
+			if !force && matches!(match_state_id("", &read_id), WhoOwns::Other) {
+				return GenErr!("nonvolatile: state \"{}\" is still open in shared mode by {}", name, describe_owner(&read_id));
+			}
+			let _ = remove_file(&lockfile);
+		}
+		Ok(())
 	}
+
+
+	///Load the state of the given name, taking over its lock from the current owner if the
+	///owner is no longer running (or regardless, if `force` is set). See `State::force_unlock`.
+	pub fn load_steal(name: &str, force: bool) -> Result<State> {
+		State::force_unlock(name, force)?;
+		State::load(name)
+	}
+
+
+	///Load the state of the given name from a custom storage location, taking over its lock.
+	///See `State::load_steal`.
+	pub fn load_steal_from(name: &str, storage_path: &str, force: bool) -> Result<State> {
+		State::force_unlock_from(name, storage_path, force)?;
+		State::load_from(name, storage_path)
+	}
+
 	
+	///Rename the state `old` to `new`. Fails if either is open (including by this process),
+	///or if there's already a state called `new`, unless `overwrite` is set. See `move_to`
+	///for how the state is moved.
+	///
+	///### Example
+	///
+	///```rust
+	///State::rename("my_app", "my_renamed_app", false)?;
+	///let state = State::load("my_renamed_app")?;
+	///```
+	pub fn rename(old: &str, new: &str, overwrite: bool) -> Result<()> {
+		State::rename_from(old, new, &get_storage_dir()?, overwrite)
+	}
+	
+	
+	///Rename a state in a custom storage location. See `State::rename`.
+	pub fn rename_from(old: &str, new: &str, storage_path: &str, overwrite: bool) -> Result<()> {
+		relocate::relocate(old, storage_path, new, storage_path, overwrite, false)
+	}
+	
+	
+	///Copy the state `name` to a new state called `dest_name`, including any values stored 
+	///outside the manifest. Fails if either is open, or if there's already a state called 
+	///`dest_name`, unless `overwrite` is set.
+	///
+	///The copy is put together next to the destination, and renamed into place once it's 
+	///complete, so the destination never holds part of it. When overwriting, the old state is
+	///kept if anything goes wrong before then. A destination directory that isn't a 
+	///nonvolatile state is never overwritten.
+	///
+	///### Example
+	///
+	///```rust
+	///State::copy("my_app", "my_app_backup", true)?;
+	///```
+	pub fn copy(name: &str, dest_name: &str, overwrite: bool) -> Result<()> {
+		State::copy_from(name, dest_name, &get_storage_dir()?, overwrite)
+	}
 	
+	
+	///Copy a state in a custom storage location. See `State::copy`.
+	pub fn copy_from(name: &str, dest_name: &str, storage_path: &str, overwrite: bool) -> Result<()> {
+		relocate::relocate(name, storage_path, dest_name, storage_path, overwrite, true)
+	}
+	
+	
+	///Move the state `name` from the storage location `from_dir` to `to_dir`, keeping its name.
+	///If both are on the same filesystem, the state is simply renamed. Otherwise, it's copied
+	///(see `State::copy`), and the original is only removed once the copy is complete. If 
+	///removing it fails, the error says so, but the move itself has already happened.
+	///
+	///### Example
+	///
+	///```rust
+	///let data_dir = nonvolatile::storage_dir(StateKind::Data)?;
+	///let config_dir = nonvolatile::storage_dir(StateKind::Config)?;
+	///State::move_to("my_cache", &config_dir, &data_dir, false)?;
+	///```
+	pub fn move_to(name: &str, from_dir: &str, to_dir: &str, overwrite: bool) -> Result<()> {
+		relocate::relocate(name, from_dir, name, to_dir, overwrite, false)
+	}
+
+
+
+
 	///Destroy the state of the given name. If no state exists with that name, nothing happens.
 	///
+	///Fails if the state is open, unless `force` is set, in which case the lock is taken over
+	///first (see `State::force_unlock`). Also fails if the directory that would be deleted 
+	///isn't a nonvolatile state.
+	///
 	///### Example
 	///
 	///```rust
@@ -582,21 +1619,22 @@ impl State {
 	/// // ... 
 	///
 	/// //oh, turns out we don't need those settings stored after all...?
-	///State::destroy_state("foo");
+	///State::destroy_state("foo", false)?;
 	///```
-	pub fn destroy_state(name: &str) {
-		if let Err(_) = check_path_valid(name) {
-			return;
+	pub fn destroy_state(name: &str, force: bool) -> Result<()> {
+		State::destroy_state_from(name, &get_storage_dir()?, force)?;
+		//otherwise the next `load` would just migrate the legacy copy back
+		if let Some(dir) = legacy_storage_dir() {
+			if FsBackend::new(&dir).exists(name)? {
+				State::destroy_state_from(name, &dir, force)?;
+			}
 		}
-		if let Ok(dir) = get_storage_dir() {
-			let path = format!("{}/{}", dir, name);
-			let _ = remove_dir_all(path);
-		} 
+		Ok(())
 	}
 
 
 	///Destroy the state of the given name at the given custom storage location. 
-	///If no state exists with that name at that location, nothing happens.
+	///If no state exists with that name at that location, nothing happens. See `State::destroy_state`.
 	///
 	///### Example
 	///
@@ -608,21 +1646,222 @@ impl State {
 	/// // ... 
 	///
 	/// //oh, turns out we don't need those settings stored after all...?
-	///State::destroy_state_from("foo", ".");
+	///State::destroy_state_from("foo", ".", false)?;
 	///```
-	pub fn destroy_state_from(name: &str, storage_path: &str) {
-		if let Err(_) = check_path_valid(name) {
-			return;
+	pub fn destroy_state_from(name: &str, storage_path: &str, force: bool) -> Result<()> {
+		check_path_valid(name)?;
+		let backend = FsBackend::new(storage_path);
+		let path = backend.state_path(name);
+		match metadata(&path) {
+			Ok(_) => (),
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(e.into()),
+		}
+		if !backend.exists(name)? {
+			return GenErr!("nonvolatile: {} is not a nonvolatile state (it has no manifest)", path);
+		}
+		if force {
+			State::force_unlock_from(name, storage_path, true)?;
+		}
+		//hold the lock while deleting, so that nobody can open the state halfway through
+		let owner = get_state_id()?;
+		backend.acquire_lock(name, &owner)?;
+		if let Err(e) = backend.destroy(name) {
+			let _ = backend.release_lock(name, &owner);
+			return Err(e);
 		}
-		let path = format!("{}/{}", storage_path, name);
-		let _ = remove_dir_all(path);
+		Ok(())
 	}
 	
 }
 
 
+#[cfg(feature = "encryption")]
+impl State {
+
+	///Create a new encrypted State object with the given name.
+	///
+	///Behaves like `new`, except that the manifest is encrypted with XChaCha20-Poly1305 
+	///using `key` every time it is written. The state can only be opened again with 
+	///`load_encrypted` and the same key. Requires the `encryption` feature.
+	///
+	///### Example
+	///
+	///```rust
+	///let key = EncryptionKey::Passphrase(String::from("correct horse battery staple"));
+	///let mut state = State::new_encrypted("my_state", &key)?;
+	///state.set("api_token", "hunter2")?;
+	///```
+	pub fn new_encrypted(name: &str, key: &EncryptionKey) -> Result<State> {
+		State::builder(name).encryption_key(key.clone()).overwrite(true).open()
+	}
+
+
+	///Create a new encrypted State object with the given name, and a custom storage location.
+	///
+	///See `new_from` and `new_encrypted`.
+	///
+	///### Example
+	///
+	///```rust
+	///let key = EncryptionKey::Raw(my_32_byte_key);
+	///let mut state = State::new_encrypted_from("my_state", ".", &key)?;  // create the state in the CWD
+	///```
+	pub fn new_encrypted_from(name: &str, storage_path: &str, key: &EncryptionKey) -> Result<State> {
+		State::builder(name).storage_dir(storage_path).encryption_key(key.clone()).overwrite(true).open()
+	}
+
+
+	///Attempt to load an encrypted state of the given name.
+	///
+	///If there is no state with that name, the state isn't encrypted, or `key` is not the key 
+	///the state was created with, an error will be returned. Requires the `encryption` feature.
+	///
+	///### Example
+	///
+	///```rust
+	///let key = EncryptionKey::Passphrase(String::from("correct horse battery staple"));
+	///let state = State::load_encrypted("my_state", &key)?;
+	///let token: String = state.get("api_token").unwrap();
+	///```
+	pub fn load_encrypted(name: &str, key: &EncryptionKey) -> Result<State> {
+		State::builder(name).encryption_key(key.clone()).open()
+	}
+
+
+	///Attempt to load an encrypted state of the given name from a custom storage location.
+	///
+	///See `load_from` and `load_encrypted`.
+	///
+	///### Example
+	///
+	///```rust
+	///let state = State::load_encrypted_from("my_state", ".", &key)?;  // load state from the CWD
+	///```
+	pub fn load_encrypted_from(name: &str, storage_path: &str, key: &EncryptionKey) -> Result<State> {
+		State::builder(name).storage_dir(storage_path).encryption_key(key.clone()).open()
+	}
+
+
+	///Load an encrypted state of the given name if it exists. If not, create a new encrypted 
+	///state and return that.
+	///
+	///Unlike `load_else_create`, a state that exists but can't be decrypted with `key` is 
+	///not overwritten; the decryption error is returned instead.
+	///
+	///### Example
+	///
+	///```rust
+	///let mut state = State::load_else_create_encrypted("my_state", &key)?;
+	///```
+	pub fn load_else_create_encrypted(name: &str, key: &EncryptionKey) -> Result<State> {
+		State::builder(name).encryption_key(key.clone()).create_if_missing(true).open()
+	}
+
+
+	///Load an encrypted state of the given name from the given custom storage location if it
+	///exists. If not, create a new encrypted state at the custom location and return that.
+	///
+	///See `load_else_create_from` and `load_else_create_encrypted`.
+	///
+	///### Example
+	///
+	///```rust
+	///let mut state = State::load_else_create_encrypted_from("my_state", ".", &key)?;
+	///```
+	pub fn load_else_create_encrypted_from(name: &str, storage_path: &str, key: &EncryptionKey) -> Result<State> {
+		State::builder(name).storage_dir(storage_path).encryption_key(key.clone()).create_if_missing(true).open()
+	}
+
+
+	///Provide the key used by `set_secret` and `get_secret` for this state.
+	///
+	///Secrets can be used on any state, encrypted or not; only the secret values themselves
+	///are encrypted with this key. If the state already holds secrets and `key` can't decrypt 
+	///them, an error is returned and the previous key (if any) is kept.
+	///
+	///### Example
+	///
+	///```rust
+	///let mut state = State::load_else_create("my_state")?;
+	///state.set_secret_key(&EncryptionKey::Keyfile(PathBuf::from("/home/me/.my_state.key")))?;
+	///```
+	pub fn set_secret_key(&mut self, key: &EncryptionKey) -> Result<()> {
+		let new_salt = self.secret_salt.is_none();
+		let salt = match &self.secret_salt {
+			Some(salt) => salt.clone(),
+			None => encryption::new_salt(),
+		};
+		let cipher = SecretCipher::new(key, &salt)?;
+		if let Some((var, sealed)) = self.secrets.iter().next() {
+			cipher.open(var, sealed)?;
+		}
+		self.secret_cipher = Some(cipher);
+		if new_salt {
+			self.commit(None, |state| state.secret_salt = Some(salt))?;
+		}
+		Ok(())
+	}
+
+
+	///Set a secret variable with name `var` and value `value`.
+	///
+	///Works like `set`, except that the value is encrypted with the key given to 
+	///`set_secret_key` before it's stored, is left out of `Debug` output, and can only be 
+	///read back with `get_secret`. Returns an error if no secret key has been set.
+	///
+	///Secrets never leave the state in plaintext: there is no export of a state's values, 
+	///`copy`, `rename` and `move_to` carry secrets over still encrypted (so the copy reads 
+	///them with the same key), and `import_file` never reads them, and counts a key that's a
+	///secret as a conflict (so only `MergeMode::Overwrite` replaces it with a plain value).
+	///
+	///### Example
+	///
+	///```rust
+	///state.set_secret("api_token", "hunter2")?;
+	///```
+	pub fn set_secret<T>(&mut self, var: &str, value: T) -> Result<()> where T: Serialize {
+		let sealed = match &self.secret_cipher {
+			Some(cipher) => cipher.seal(var, &serde_yaml::to_string(&value)?)?,
+			None => return GenErr!("nonvolatile: cannot set secret \"{}\" before State::set_secret_key is called", var),
+		};
+		self.commit(Some(var), |state| {
+			let _ = state.secrets.insert(String::from(var), sealed);
+			let _ = state.items.remove(var);
+			let _ = state.blobs.remove(var);
+		})
+	}
+
+
+	///Try to retrieve a secret variable that was previously stored with `set_secret`.
+	///
+	///The return will be the value if it can be found, or None if no secret with that name
+	///is stored, no secret key has been set, or the stored value had an incompatible type.
+	///
+	///### Example
+	///
+	///```rust
+	///let token: String = state.get_secret("api_token").unwrap();
+	///```
+	pub fn get_secret<T>(&self, var: &str) -> Option<T> where for<'a> T: Deserialize<'a> {
+		let sealed = self.secrets.get(var)?;
+		let item = self.secret_cipher.as_ref()?.open(var, sealed).ok()?;
+		serde_yaml::from_str(&item).ok()
+	}
+}
+
+
+//best-effort; `State::close` reports what goes wrong
 impl Drop for State {
 	fn drop(&mut self) {
-		let _ = remove_file(&self.lockfile_path);
+		if self.read_only || self.released {
+			return;
+		}
+		//stop renewing the lease before giving up the lock, or it could be renewed after
+		let _ = self.lease.take();
+		let _ = self.backend.release_lock(&self.name, &self.identifier);
+		if let Some(temp_dir) = self.temp_dir.take() {
+			let _ = remove_dir_all(temp_dir);
+		}
 	}
 }
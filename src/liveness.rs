@@ -0,0 +1,106 @@
+/*
+cheap checks for whether the process that owns a lock is still alive
+*/
+
+use std::env;
+use std::process;
+
+
+///The path of the current executable, as recorded in lock IDs.
+pub(crate) fn current_exe() -> String {
+	match env::current_exe() {
+		Ok(path) => path.to_string_lossy().to_string(),
+		Err(_) => String::new(),
+	}
+}
+
+
+///When the current process started, as recorded in lock IDs. See `process_start_time`.
+pub(crate) fn current_start_time() -> Option<u64> {
+	process_start_time(process::id())
+}
+
+
+///An ID that changes every time the machine boots, so that PIDs recorded before a reboot
+///aren't mistaken for processes running now. Only available on Linux.
+#[cfg(target_os = "linux")]
+pub(crate) fn current_boot_id() -> Option<String> {
+	let boot_id = std::fs::read_to_string("/proc/sys/kernel/random/boot_id").ok()?;
+	Some(String::from(boot_id.trim()))
+}
+
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn current_boot_id() -> Option<String> {
+	None
+}
+
+
+///When the given process started, in clock ticks since boot. Together with the PID this
+///identifies a process even if its PID gets reused. Only available on Linux.
+#[cfg(target_os = "linux")]
+pub(crate) fn process_start_time(pid: u32) -> Option<u64> {
+	let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+	//the command name (field 2) may contain spaces and parentheses, so skip past it
+	let fields = &stat[stat.rfind(')')? + 1..];
+	//starttime is field 22; `fields` starts at field 3
+	fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn process_start_time(_pid: u32) -> Option<u64> {
+	None
+}
+
+
+///Whether the process `pid` is alive and is still the process that was running `exe`
+///(and started at `start_time`, if known).
+#[cfg(target_os = "linux")]
+pub(crate) fn is_alive(pid: u32, exe: &str, start_time: Option<u64>) -> bool {
+	use std::io::ErrorKind;
+
+	match std::fs::read_link(format!("/proc/{}/exe", pid)) {
+		Ok(path) => {
+			let path = path.to_string_lossy();
+			//if the executable was replaced (e.g. by an upgrade) since the process started
+			let path = path.trim_end_matches(" (deleted)");
+			if path != exe {
+				return false;
+			}
+		},
+		//we can't see other users' executables, but the process is there
+		Err(ref e) if e.kind() == ErrorKind::PermissionDenied => (),
+		Err(_) => return false,
+	}
+	match start_time {
+		Some(start_time) => process_start_time(pid) == Some(start_time),
+		None => true,
+	}
+}
+
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub(crate) fn is_alive(pid: u32, _exe: &str, _start_time: Option<u64>) -> bool {
+	if pid == 0 || pid > i32::MAX as u32 {
+		return false;
+	}
+	//signal 0 only checks whether the process exists; EPERM means it does, but isn't ours
+	let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+	result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+
+#[cfg(not(unix))]
+pub(crate) fn is_alive(pid: u32, exe: &str, _start_time: Option<u64>) -> bool {
+	use sysinfo::{System, SystemExt, ProcessExt, Pid};
+
+	let mut system = System::new();
+	if !system.refresh_process(pid as Pid) {
+		return false;
+	}
+	match system.get_process(pid as Pid) {
+		Some(process) => process.exe().to_string_lossy() == exe,
+		None => false,
+	}
+}
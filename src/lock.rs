@@ -0,0 +1,315 @@
+/*
+inspecting who holds a state's lock
+*/
+
+use std::fmt;
+use std::fs::{read_to_string, rename, remove_file};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use generic_error::{Result, GenErr, GenericError};
+
+use crate::{liveness, permissions, check_path_valid, canonicalize_path, get_storage_dir, LOCKFILE_NAME};
+
+
+const RENEW_TMP_PREFIX: &str = "~rust_nonvolatile.renew.";
+
+
+///Who holds a state's lock, as recorded in its lockfile. See `nonvolatile::lock_info`.
+///
+///Lockfiles written by older versions of nonvolatile don't record when the lock was
+///acquired or by which host, so those fields may be `None`.
+///
+///Whether the owner is still alive can only be checked directly if it's running on this
+///machine. An owner on another host (e.g. sharing the storage directory over NFS) is assumed
+///to be alive, unless the lockfile has a lease timestamp that has gone stale.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LockInfo {
+	///The PID of the owning process.
+	pub pid: u32,
+	///The path of the owning process's executable.
+	pub exe: String,
+	///When the lock was acquired.
+	pub acquired: Option<SystemTime>,
+	///The hostname of the machine the owner is running on.
+	pub hostname: Option<String>,
+	///The boot ID of the machine the owner is running on (Linux only).
+	pub boot_id: Option<String>,
+	///When the owner's lease on the lock runs out, unless it's renewed.
+	pub lease_expires: Option<SystemTime>,
+	pub(crate) random: Option<u32>,
+	pub(crate) start_time: Option<u64>,
+}
+
+
+///Format the ID that gets written to the lockfile.
+pub(crate) fn format_lock_id(pid: u32, exe: &str, start_time: Option<u64>) -> String {
+	let start_time = start_time.map(|t| t.to_string()).unwrap_or_default();
+	let acquired = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+	let boot_id = liveness::current_boot_id().unwrap_or_default();
+	format!(
+		"{}\n{}\n{}\n{}\n{}\n{}\n{}",
+		pid, rand::random::<u32>(), exe, start_time, acquired, whoami::hostname(), boot_id,
+	)
+}
+
+
+///Whether two lock IDs were written by the same `State`. Lock IDs can't just be compared,
+///since the lease timestamp changes when the lease is renewed.
+pub(crate) fn same_owner(id: &str, other_id: &str) -> bool {
+	if id == other_id {
+		return true;
+	}
+	match (LockInfo::parse(id), LockInfo::parse(other_id)) {
+		(Some(a), Some(b)) => {
+			a.pid == b.pid && a.random == b.random && a.hostname == b.hostname && a.boot_id == b.boot_id
+		},
+		_ => false,
+	}
+}
+
+
+///Add a lease to a lock ID, which runs out `lease` from now. The expiry time is recorded in
+///milliseconds, since leases can be shorter than a second.
+pub(crate) fn with_lease(id: &str, lease: Duration) -> String {
+	let expires = (SystemTime::now() + lease).duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+	let mut parts: Vec<&str> = id.split('\n').collect();
+	parts.resize(7, "");
+	format!("{}\n{}", parts.join("\n"), expires)
+}
+
+
+fn parse_time(secs: &str) -> Option<SystemTime> {
+	secs.parse().ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+
+impl LockInfo {
+
+	///Parse a lock ID. Returns `None` if it isn't one.
+	pub(crate) fn parse(id: &str) -> Option<LockInfo> {
+		let parts: Vec<&str> = id.split('\n').collect();
+		//lockfiles written by older versions only have the first three fields
+		if parts.len() < 3 {
+			return None;
+		}
+		let field = |i: usize| parts.get(i).filter(|s| !s.is_empty());
+		Some(LockInfo {
+			pid: parts[0].parse().ok()?,
+			exe: String::from(parts[2]),
+			acquired: field(4).and_then(|s| parse_time(s)),
+			hostname: field(5).map(|s| String::from(*s)),
+			boot_id: field(6).map(|s| String::from(*s)),
+			lease_expires: field(7)
+				.and_then(|s| s.parse().ok())
+				.map(|millis| UNIX_EPOCH + Duration::from_millis(millis)),
+			random: parts[1].parse().ok(),
+			start_time: field(3).and_then(|s| s.parse().ok()),
+		})
+	}
+
+
+	///Whether the owner is running on this machine.
+	pub fn is_local(&self) -> bool {
+		match &self.hostname {
+			Some(hostname) => *hostname == whoami::hostname(),
+			None => true,
+		}
+	}
+
+
+	///Whether the owning process is still running (or, if it's on another host, whether it
+	///still has a lease on the lock).
+	pub fn is_alive(&self) -> bool {
+		if let Some(lease_expires) = self.lease_expires {
+			if lease_expires < SystemTime::now() {
+				return false;
+			}
+		}
+		if !self.is_local() {
+			//we can't see processes on other hosts
+			return true;
+		}
+		if let (Some(boot_id), Some(my_boot_id)) = (&self.boot_id, liveness::current_boot_id()) {
+			//the owner was running before the last reboot
+			if *boot_id != my_boot_id {
+				return false;
+			}
+		}
+		liveness::is_alive(self.pid, &self.exe, self.start_time)
+	}
+}
+
+
+impl fmt::Display for LockInfo {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "pid {} ({})", self.pid, self.exe)?;
+		if let Some(hostname) = &self.hostname {
+			write!(f, " on {}", hostname)?;
+		}
+		if let Some(age) = self.acquired.and_then(|t| t.elapsed().ok()) {
+			write!(f, ", acquired {}s ago", age.as_secs())?;
+		}
+		if let Some(lease_expires) = self.lease_expires {
+			match lease_expires.duration_since(SystemTime::now()) {
+				Ok(left) => write!(f, ", lease expires in {}s", left.as_secs())?,
+				Err(_) => write!(f, ", lease expired")?,
+			}
+		}
+		Ok(())
+	}
+}
+
+
+///Read the lockfile at `lockfile_path`, if there is one.
+pub(crate) fn read_lock_info(lockfile_path: &str) -> Result<Option<LockInfo>> {
+	let id = match read_to_string(lockfile_path) {
+		Ok(id) => id,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(e.into()),
+	};
+	match LockInfo::parse(&id) {
+		Some(info) => Ok(Some(info)),
+		None => GenErr!("nonvolatile: lockfile {} is corrupt", lockfile_path),
+	}
+}
+
+
+///Find out who holds the lock on the state with the given name, if anyone.
+///
+///A lock is only reported if its lockfile exists; use `LockInfo::is_alive` to check whether
+///the owner is still running (a lock left behind by a dead process is taken over by the
+///next `load`).
+///
+///### Example
+///
+///```rust
+///if let Some(owner) = nonvolatile::lock_info("my_state")? {
+///    println!("my_state is locked by {}", owner);
+///}
+///```
+pub fn lock_info(name: &str) -> Result<Option<LockInfo>> {
+	lock_info_from(name, &get_storage_dir()?)
+}
+
+
+///Find out who holds the lock on the state with the given name in a custom storage location.
+///See `lock_info`.
+pub fn lock_info_from(name: &str, storage_path: &str) -> Result<Option<LockInfo>> {
+	check_path_valid(name)?;
+	let path = canonicalize_path(format!("{}/{}", storage_path, name));
+	read_lock_info(&format!("{}/{}", path, LOCKFILE_NAME))
+}
+
+
+///Keeps renewing the lease on a lock from a background thread, until dropped.
+pub(crate) struct LeaseRenewer {
+	lockfile_path: String,
+	identifier: String,
+	//when the lease runs out, as of the last renewal. `None` once the lock is lost.
+	expires: Arc<Mutex<Option<SystemTime>>>,
+	stop: Option<Sender<()>>,
+	handle: Option<JoinHandle<()>>,
+}
+
+
+fn lock_expiry(expires: &Mutex<Option<SystemTime>>) -> MutexGuard<'_, Option<SystemTime>> {
+	match expires.lock() {
+		Ok(expires) => expires,
+		Err(poisoned) => poisoned.into_inner(),
+	}
+}
+
+
+impl LeaseRenewer {
+
+	///Renew the lease on `lockfile_path` (held by `identifier`) every third of `lease`.
+	pub(crate) fn start(lockfile_path: &str, identifier: &str, lease: Duration) -> LeaseRenewer {
+		let (tx, rx) = channel();
+		let initial = read_lock_info(lockfile_path).ok().flatten().and_then(|info| info.lease_expires);
+		let expires = Arc::new(Mutex::new(Some(initial.unwrap_or_else(|| SystemTime::now() + lease))));
+		let (path, id, renewed) = (String::from(lockfile_path), String::from(identifier), expires.clone());
+		let handle = thread::spawn(move || {
+			while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(lease / 3) {
+				//the lease written to the lockfile runs out a little later than this
+				let until = SystemTime::now() + lease;
+				let mut expires = lock_expiry(&renewed);
+				if expires.is_none() {
+					break;
+				}
+				match renew_lease(&path, &id, lease) {
+					Ok(true) => *expires = Some(until),
+					//someone else has the lock now; there's nothing left to renew
+					Ok(false) => {
+						*expires = None;
+						break;
+					},
+					//try again next time; if it keeps failing, the lease will run out
+					Err(_) => (),
+				}
+			}
+		});
+		LeaseRenewer {
+			lockfile_path: String::from(lockfile_path),
+			identifier: String::from(identifier),
+			expires,
+			stop: Some(tx),
+			handle: Some(handle),
+		}
+	}
+
+
+	///Fail if the lock has been lost: either the lease ran out, or someone else has taken the
+	///lock over. Once lost, the lock stays lost, since whoever took it over may have changed
+	///the state in the meantime.
+	pub(crate) fn check(&self) -> Result<()> {
+		let mut expires = lock_expiry(&self.expires);
+		let held = match *expires {
+			Some(until) if SystemTime::now() < until => match read_to_string(&self.lockfile_path) {
+				Ok(read_id) => same_owner(&read_id, &self.identifier),
+				Err(_) => false,
+			},
+			_ => false,
+		};
+		if !held {
+			*expires = None;
+			return GenErr!("nonvolatile: lost the lock {} (the lease ran out, or the lock was taken over)", self.lockfile_path);
+		}
+		Ok(())
+	}
+}
+
+
+impl Drop for LeaseRenewer {
+	fn drop(&mut self) {
+		let _ = self.stop.take();
+		if let Some(handle) = self.handle.take() {
+			let _ = handle.join();
+		}
+	}
+}
+
+
+///Rewrite the lockfile with a fresh lease, if it's still ours. The new lockfile is written
+///next to it and renamed into place, so nobody ever reads a half-written lock ID.
+fn renew_lease(lockfile_path: &str, identifier: &str, lease: Duration) -> Result<bool> {
+	match read_to_string(lockfile_path) {
+		Ok(read_id) if same_owner(&read_id, identifier) => (),
+		_ => return Ok(false),
+	}
+	let dir = match Path::new(lockfile_path).parent() {
+		Some(dir) => dir.to_string_lossy().to_string(),
+		None => return GenErr!("nonvolatile: lockfile {} has no parent directory", lockfile_path),
+	};
+	let tmp_path = format!("{}/{}{:08x}", dir, RENEW_TMP_PREFIX, rand::random::<u32>());
+	let result = permissions::create_file(&tmp_path)
+		.and_then(|mut file| Ok(file.write_all(with_lease(identifier, lease).as_bytes())?))
+		.and_then(|_| Ok(rename(&tmp_path, lockfile_path)?));
+	if result.is_err() {
+		let _ = remove_file(&tmp_path);
+	}
+	result.map(|_| true)
+}
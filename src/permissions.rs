@@ -0,0 +1,225 @@
+/*
+file permissions and ownership checks for nonvolatile
+*/
+
+use std::fs::{DirBuilder, File, OpenOptions};
+#[cfg(unix)]
+use std::io::ErrorKind;
+use std::sync::RwLock;
+use lazy_static::lazy_static;
+use generic_error::{Result, GenErr, GenericError};
+
+
+///What to do when a state directory looks unsafe to use at load time
+///(i.e. it is world-writable, or owned by another user). Unless this is `Ignore`, states
+///with broader permissions than `dir_mode` and `file_mode` are also narrowed down to them 
+///when they're opened for writing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InsecureAction {
+	///Return an error instead of opening the state.
+	Refuse,
+	///Print a warning to stderr, but open the state anyway.
+	Warn,
+	///Don't check.
+	Ignore,
+}
+
+
+///Permissions used when creating state directories and files, and how strictly
+///existing state directories are checked. Only has an effect on Unix.
+///
+///The default is `0o700` for directories, `0o600` for files, and refusing insecure
+///state directories.
+///
+///### Example
+///
+///```rust
+/// // let the rest of my group read settings
+///nonvolatile::set_permissions(Permissions {
+///    dir_mode: 0o750,
+///    file_mode: 0o640,
+///    on_insecure: InsecureAction::Warn,
+///});
+///```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Permissions {
+	pub dir_mode: u32,
+	pub file_mode: u32,
+	pub on_insecure: InsecureAction,
+}
+
+
+impl Default for Permissions {
+	fn default() -> Permissions {
+		Permissions {
+			dir_mode: 0o700,
+			file_mode: 0o600,
+			on_insecure: InsecureAction::Refuse,
+		}
+	}
+}
+
+
+lazy_static! {
+	static ref PERMISSIONS: RwLock<Permissions> = RwLock::new(Permissions::default());
+}
+
+
+///Set the permissions used for every state created or loaded afterwards by this process.
+pub fn set_permissions(permissions: Permissions) {
+	match PERMISSIONS.write() {
+		Ok(mut p) => *p = permissions,
+		Err(poisoned) => *poisoned.into_inner() = permissions,
+	}
+}
+
+
+///Get the permissions currently used for creating and checking states.
+pub fn get_permissions() -> Permissions {
+	match PERMISSIONS.read() {
+		Ok(p) => *p,
+		Err(poisoned) => *poisoned.into_inner(),
+	}
+}
+
+
+///`create_dir_all`, but any directories created get `dir_mode`.
+pub(crate) fn create_dir(path: &str) -> Result<()> {
+	let mut builder = DirBuilder::new();
+	builder.recursive(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::DirBuilderExt;
+		builder.mode(get_permissions().dir_mode);
+	}
+	builder.create(path)?;
+	Ok(())
+}
+
+
+///Open a file for writing, truncating it. If the file gets created, it gets `file_mode`.
+pub(crate) fn create_file(path: &str) -> Result<File> {
+	let mut options = OpenOptions::new();
+	options.write(true).create(true).truncate(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(get_permissions().file_mode);
+	}
+	Ok(options.open(path)?)
+}
+
+
+///Open a file for appending. If the file gets created, it gets `file_mode`.
+pub(crate) fn append_file(path: &str) -> Result<File> {
+	let mut options = OpenOptions::new();
+	options.append(true).create(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(get_permissions().file_mode);
+	}
+	Ok(options.open(path)?)
+}
+
+
+///Create a file for writing, failing if it already exists. It gets `file_mode`.
+pub(crate) fn create_new_file(path: &str) -> std::io::Result<File> {
+	let mut options = OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(get_permissions().file_mode);
+	}
+	options.open(path)
+}
+
+
+///Make sure nobody else can write to the state directory at `path`, and if `check_owner` is 
+///set, that it belongs to the current user.
+#[cfg(unix)]
+pub(crate) fn check_dir(path: &str, check_owner: bool) -> Result<()> {
+	use std::os::unix::fs::MetadataExt;
+
+	let action = get_permissions().on_insecure;
+	if action == InsecureAction::Ignore {
+		return Ok(());
+	}
+	let mdata = std::fs::metadata(path)?;
+	let my_uid = unsafe { libc::geteuid() };
+
+	let problem = if check_owner && mdata.uid() != my_uid {
+		format!("is owned by uid {}, not the current user (uid {})", mdata.uid(), my_uid)
+	}
+	else if mdata.mode() & 0o002 != 0 {
+		format!("is world-writable (mode {:o})", mdata.mode() & 0o777)
+	}
+	else {
+		return Ok(());
+	};
+
+	match action {
+		InsecureAction::Refuse => GenErr!("nonvolatile: refusing to use state directory {}: it {}", path, problem),
+		_ => {
+			eprintln!("nonvolatile: warning: state directory {} {}", path, problem);
+			Ok(())
+		},
+	}
+}
+
+
+///Take away any permissions that the state directory at `path`, or anything in it, has
+///beyond `dir_mode` and `file_mode`, e.g. because it was created by an older version of 
+///nonvolatile. Files belonging to other users are left alone.
+#[cfg(unix)]
+pub(crate) fn restrict(path: &str) -> Result<()> {
+	let permissions = get_permissions();
+	if permissions.on_insecure == InsecureAction::Ignore {
+		return Ok(());
+	}
+	restrict_tree(std::path::Path::new(path), &permissions)
+}
+
+
+#[cfg(unix)]
+fn restrict_tree(path: &std::path::Path, permissions: &Permissions) -> Result<()> {
+	use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+	//files can come and go while we look, e.g. a manifest being replaced
+	let mdata = match std::fs::symlink_metadata(path) {
+		Ok(mdata) => mdata,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(e.into()),
+	};
+	let allowed = match (mdata.is_dir(), mdata.is_file()) {
+		(true, _) => permissions.dir_mode,
+		(false, true) => permissions.file_mode,
+		_ => return Ok(()),
+	};
+	let mode = mdata.mode() & 0o777;
+	if mode & !allowed != 0 && mdata.uid() == unsafe { libc::geteuid() } {
+		match std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & allowed)) {
+			Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+			_ => (),
+		}
+	}
+	if mdata.is_dir() {
+		for entry in std::fs::read_dir(path)? {
+			restrict_tree(&entry?.path(), permissions)?;
+		}
+	}
+	Ok(())
+}
+
+
+#[cfg(not(unix))]
+pub(crate) fn restrict(_path: &str) -> Result<()> {
+	Ok(())
+}
+
+
+#[cfg(not(unix))]
+pub(crate) fn check_dir(_path: &str, _check_owner: bool) -> Result<()> {
+	Ok(())
+}
@@ -0,0 +1,213 @@
+/*
+copying, renaming and moving states between names and storage directories
+*/
+
+use std::fs::{copy, metadata, read_dir, remove_dir_all, remove_file, rename};
+use std::io::ErrorKind;
+use generic_error::{Result, GenErr, GenericError};
+
+use crate::{permissions, check_path_valid, get_state_id, Backend, FsBackend};
+
+
+//a copy is put together in a directory starting with this, next to where it's going, and a
+//state being replaced is moved aside to one. State names can't start with a dot, so these 
+//can't clash with a state.
+const TMP_PREFIX: &str = ".~tmp.";
+
+
+///A path in `backend`'s directory for `name` to be put together in, or moved aside to.
+fn tmp_path(backend: &FsBackend, name: &str, suffix: &str) -> String {
+	backend.state_path(&format!("{}{}.{:08x}{}", TMP_PREFIX, name, rand::random::<u32>(), suffix))
+}
+
+
+///Whether `a` and `b` are on the same filesystem, so that one can be renamed into the other.
+#[cfg(unix)]
+fn same_filesystem(a: &str, b: &str) -> bool {
+	use std::os::unix::fs::MetadataExt;
+	match (metadata(a), metadata(b)) {
+		(Ok(a), Ok(b)) => a.dev() == b.dev(),
+		_ => false,
+	}
+}
+
+
+#[cfg(not(unix))]
+fn same_filesystem(_a: &str, _b: &str) -> bool {
+	false
+}
+
+
+///Whether a file in a state's directory belongs to whoever has the state open (lockfiles), or
+///to a write that never finished (temporary files), rather than to the state itself.
+fn is_transient(file_name: &str) -> bool {
+	file_name.starts_with('~') || file_name.ends_with("_tmp")
+}
+
+
+///Copy everything in `from` but transient files into `to`.
+fn copy_files(from: &str, to: &str) -> Result<()> {
+	permissions::create_dir(to)?;
+	for entry in read_dir(from)? {
+		let entry = entry?;
+		let name = entry.file_name().to_string_lossy().to_string();
+		if is_transient(&name) {
+			continue;
+		}
+		let (from_path, to_path) = (format!("{}/{}", from, name), format!("{}/{}", to, name));
+		match entry.file_type()?.is_dir() {
+			true => copy_files(&from_path, &to_path)?,
+			false => {
+				let _ = copy(&from_path, &to_path)?;
+			},
+		}
+	}
+	Ok(())
+}
+
+
+///Whether the directory at `path` holds anything but lockfiles.
+fn has_contents(path: &str) -> Result<bool> {
+	let entries = match read_dir(path) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+		Err(e) => return Err(e.into()),
+	};
+	for entry in entries {
+		if !entry?.file_name().to_string_lossy().starts_with('~') {
+			return Ok(true);
+		}
+	}
+	Ok(false)
+}
+
+
+///Put the complete state in `new_path` in place at `to_path`. If `old_path` is given, the 
+///state already at `to_path` is moved there first, put back if that fails, and removed 
+///afterwards.
+fn swap_in(new_path: &str, to_path: &str, old_path: Option<&str>) -> Result<()> {
+	let old_path = match old_path {
+		Some(old_path) => old_path,
+		None => {
+			rename(new_path, to_path)?;
+			return Ok(());
+		},
+	};
+	rename(to_path, old_path)?;
+	if let Err(e) = rename(new_path, to_path) {
+		if let Err(restore_e) = rename(old_path, to_path) {
+			return GenErr!("nonvolatile: failed to put the new state in place at {} ({}), and to put the old one back; it's been left in {} ({})", to_path, e, old_path, restore_e);
+		}
+		return Err(e.into());
+	}
+	let _ = remove_dir_all(old_path);
+	Ok(())
+}
+
+
+///Check that `to_name` in `to` can be written to, and lock it if it exists. Returns whether
+///there's a state there to be replaced.
+fn prepare_destination(to: &FsBackend, to_name: &str, overwrite: bool, owner: &str) -> Result<bool> {
+	let to_path = to.state_path(to_name);
+	let replacing = to.exists(to_name)?;
+	if replacing && !overwrite {
+		return GenErr!("nonvolatile: state \"{}\" already exists in {}", to_name, to.dir());
+	}
+	if !replacing && has_contents(&to_path)? {
+		return GenErr!("nonvolatile: {} is not a nonvolatile state (it has no manifest)", to_path);
+	}
+	if metadata(&to_path).is_ok() {
+		//fails if someone has the destination open, or is creating it
+		to.acquire_lock(to_name, owner)?;
+		if !replacing {
+			//nothing in there but the lockfile
+			remove_dir_all(&to_path)?;
+		}
+	}
+	permissions::create_dir(to.dir())?;
+	Ok(replacing)
+}
+
+
+///Copy the state in `from_path` into place as `to_name` in `to`. The destination must have 
+///been prepared with `prepare_destination`.
+fn copy_to(from_path: &str, to: &FsBackend, to_name: &str, replacing: bool) -> Result<()> {
+	let tmp_path = tmp_path(to, to_name, "");
+	let old_path = tmp_path.clone() + ".old";
+	let old_path = Some(old_path.as_str()).filter(|_| replacing);
+	let result = copy_files(from_path, &tmp_path).and_then(|_| swap_in(&tmp_path, &to.state_path(to_name), old_path));
+	if result.is_err() {
+		let _ = remove_dir_all(&tmp_path);
+	}
+	result
+}
+
+
+///Remove the original of a state that's been copied elsewhere.
+fn remove_source(from_path: &str) -> Result<()> {
+	//without its manifest, what's left isn't a state anymore, so it doesn't matter much if 
+	//removing the rest fails
+	remove_file(format!("{}/.manifest", from_path))?;
+	let _ = remove_dir_all(from_path);
+	Ok(())
+}
+
+
+///Copy the state `name` in `from_dir` to `to_name` in `to_dir`, and if `keep_source` is false,
+///remove the original afterwards. Both are locked throughout, so this fails if either is open.
+pub(crate) fn relocate(name: &str, from_dir: &str, to_name: &str, to_dir: &str, overwrite: bool, keep_source: bool) -> Result<()> {
+	check_path_valid(name)?;
+	check_path_valid(to_name)?;
+	let (from, to) = (FsBackend::new(from_dir), FsBackend::new(to_dir));
+	let from_path = from.state_path(name);
+	if from_path == to.state_path(to_name) {
+		return GenErr!("nonvolatile: state \"{}\" is already in {}", name, from_path);
+	}
+	if !from.exists(name)? {
+		return GenErr!("nonvolatile: state \"{}\" does not exist", name);
+	}
+
+	let owner = get_state_id()?;
+	from.acquire_lock(name, &owner)?;
+	let replacing = match prepare_destination(&to, to_name, overwrite, &owner) {
+		Ok(replacing) => replacing,
+		Err(e) => {
+			let _ = from.release_lock(name, &owner);
+			return Err(e);
+		},
+	};
+	let to_path = to.state_path(to_name);
+	let release_destination = || match replacing {
+		true => to.release_lock(to_name, &owner),
+		false => Ok(()),
+	};
+
+	//within a filesystem, a move is just a rename, which takes our lock on the source along
+	if !keep_source && same_filesystem(&from_path, to.dir()) {
+		let old_path = tmp_path(&to, to_name, ".old");
+		if let Err(e) = swap_in(&from_path, &to_path, Some(old_path.as_str()).filter(|_| replacing)) {
+			let _ = release_destination();
+			let _ = from.release_lock(name, &owner);
+			return Err(e);
+		}
+		return match to.release_lock(to_name, &owner) {
+			Ok(()) => Ok(()),
+			Err(e) => GenErr!("nonvolatile: moved state \"{}\" to {}, but couldn't unlock it: {}", name, to_path, e),
+		};
+	}
+
+	//otherwise, the state is copied. On success, our lock on the destination went away 
+	//with the state it was on.
+	if let Err(e) = copy_to(&from_path, &to, to_name, replacing) {
+		let _ = release_destination();
+		let _ = from.release_lock(name, &owner);
+		return Err(e);
+	}
+	if keep_source {
+		return from.release_lock(name, &owner);
+	}
+	match remove_source(&from_path) {
+		Ok(()) => Ok(()),
+		Err(e) => GenErr!("nonvolatile: moved state \"{}\" to {}, but couldn't remove the original at {}: {}", name, to_path, from_path, e),
+	}
+}
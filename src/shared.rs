@@ -0,0 +1,276 @@
+/*
+thread-safe shared handle for nonvolatile states
+*/
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+use std::thread::{self, JoinHandle};
+use serde::{Serialize, Deserialize};
+use generic_error::{Result, GenErr, GenericError};
+
+use crate::{editable, Format, State};
+
+
+enum WriterMsg {
+	//set (or for `None`, delete) an item
+	Change(String, Option<String>),
+	Flush(Sender<Result<()>>),
+}
+
+
+struct Shared {
+	name: String,
+	read_only: bool,
+	editable: bool,
+	items: Arc<RwLock<HashMap<String, String>>>,
+	writer: Mutex<Option<Sender<WriterMsg>>>,
+	handle: Mutex<Option<JoinHandle<Result<()>>>>,
+}
+
+
+///A handle to an open `State` that can be cloned and shared between threads.
+///
+///Reads and writes go to an in-memory copy of the state guarded by a `RwLock`, and the
+///manifest is written to disk by a background thread, so `get` never waits on file I/O and
+///`set` only waits for other `set`s. Several `set`s in quick succession are written out
+///together. Because writes happen in the background, `set` and `delete` can't report I/O
+///errors; call `flush` to wait for pending writes and get the first error, if any. In shared
+///mode (see `StateBuilder::shared`), changes are written the same way as by `State::set`, so
+///changes made by other processes in the meantime are kept.
+///
+///The underlying state stays locked until the last clone of the `SharedState` is dropped
+///or closed, at which point any pending changes are written out.
+///
+///### Example
+///
+///```rust
+///let state = State::load_else_create("my_server")?.into_shared();
+///let for_worker = state.clone();
+///thread::spawn(move || {
+///    for_worker.set("last_job", 17).unwrap();
+///});
+///let last_job: Option<u32> = state.get("last_job");
+///```
+#[derive(Clone)]
+pub struct SharedState {
+	inner: Arc<Shared>,
+}
+
+
+///Write changes out as they come in, until every `SharedState` handle is gone. Then close the
+///state, and return the first error no `flush` has reported yet.
+fn run_writer(mut state: State, rx: Receiver<WriterMsg>) -> Result<()> {
+	let mut error: Option<GenericError> = None;
+	let mut done = false;
+	while !done {
+		let mut changes: Vec<(String, Option<String>)> = Vec::new();
+		let mut replies: Vec<Sender<Result<()>>> = Vec::new();
+		match rx.recv() {
+			Ok(WriterMsg::Change(key, value)) => changes.push((key, value)),
+			Ok(WriterMsg::Flush(reply)) => replies.push(reply),
+			Err(_) => break,
+		}
+		//coalesce everything else that's already queued into this write
+		loop {
+			match rx.try_recv() {
+				Ok(WriterMsg::Change(key, value)) => changes.push((key, value)),
+				Ok(WriterMsg::Flush(reply)) => replies.push(reply),
+				Err(TryRecvError::Empty) => break,
+				Err(TryRecvError::Disconnected) => {
+					done = true;
+					break;
+				},
+			}
+		}
+		if !changes.is_empty() {
+			//in shared mode, this applies the changes on top of what other processes wrote
+			let result = state.commit(None, |state| {
+				for (key, value) in changes {
+					let _ = state.blobs.remove(&key);
+					let _ = state.secrets.remove(&key);
+					match value {
+						Some(value) => {
+							let _ = state.items.insert(key, value);
+						},
+						None => {
+							let _ = state.items.remove(&key);
+						},
+					}
+				}
+			});
+			if let Err(e) = result {
+				error = Some(e);
+			}
+		}
+		if !replies.is_empty() {
+			//errors are only reported to the next `flush`
+			for reply in replies {
+				let result = match &error {
+					Some(e) => Err(e.clone()),
+					None => Ok(()),
+				};
+				let _ = reply.send(result);
+			}
+			error = None;
+		}
+	}
+	let closed = state.close();
+	match error {
+		Some(e) => Err(e),
+		None => closed,
+	}
+}
+
+
+impl SharedState {
+
+	pub(crate) fn new(state: State) -> SharedState {
+		let items = Arc::new(RwLock::new(state.items.clone()));
+		let (tx, rx) = channel();
+		let name = state.name.clone();
+		let read_only = state.read_only;
+		let editable = state.format == Format::Editable;
+		let handle = thread::spawn(move || run_writer(state, rx));
+		SharedState {
+			inner: Arc::new(Shared {
+				name,
+				read_only,
+				editable,
+				items,
+				writer: Mutex::new(Some(tx)),
+				handle: Mutex::new(Some(handle)),
+			}),
+		}
+	}
+
+
+	fn read_items(&self) -> std::sync::RwLockReadGuard<'_, HashMap<String, String>> {
+		match self.inner.items.read() {
+			Ok(items) => items,
+			Err(poisoned) => poisoned.into_inner(),
+		}
+	}
+
+
+	///Set (or for `None`, delete) `key`, and have it written out.
+	fn modify(&self, key: &str, value: Option<String>) -> Result<()> {
+		if self.inner.read_only {
+			return GenErr!("nonvolatile: state \"{}\" was opened read-only", self.inner.name);
+		}
+		let mut items = match self.inner.items.write() {
+			Ok(items) => items,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+		match &value {
+			Some(value) => {
+				let _ = items.insert(String::from(key), value.clone());
+			},
+			None => {
+				let _ = items.remove(key);
+			},
+		}
+		//sent with the lock held, so that changes are written in the order they were made
+		self.send(WriterMsg::Change(String::from(key), value))
+	}
+
+
+	fn send(&self, msg: WriterMsg) -> Result<()> {
+		let writer = match self.inner.writer.lock() {
+			Ok(writer) => writer,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+		match writer.as_ref().map(|tx| tx.send(msg)) {
+			Some(Ok(())) => Ok(()),
+			_ => GenErr!("nonvolatile: background writer for state \"{}\" has stopped", self.inner.name),
+		}
+	}
+
+
+	///Set a variable with name `var` and value `value`. See `State::set`.
+	///
+	///The value is visible to `get` immediately, and written to storage in the background.
+	pub fn set<T>(&self, var: &str, value: T) -> Result<()> where T: Serialize {
+		if self.inner.editable {
+			editable::check_key(var)?;
+		}
+		self.modify(var, Some(serde_yaml::to_string(&value)?))
+	}
+
+
+	///Try to retrieve a variable. See `State::get`.
+	pub fn get<T>(&self, var: &str) -> Option<T> where for<'a> T: Deserialize<'a> {
+		let item = self.read_items().get(var)?.clone();
+		serde_yaml::from_str(&item).ok()
+	}
+
+
+	///Check if the given item/key exists in the state. See `State::has`.
+	pub fn has(&self, item: &str) -> bool {
+		self.read_items().contains_key(item)
+	}
+
+
+	///Delete a stored variable. See `State::delete`.
+	pub fn delete(&self, name: &str) -> Result<()> {
+		self.modify(name, None)
+	}
+
+
+	///Wait until every change made so far has been written to storage. Returns the first error
+	///from any background write since the last `flush`.
+	pub fn flush(&self) -> Result<()> {
+		let (tx, rx) = channel();
+		self.send(WriterMsg::Flush(tx))?;
+		match rx.recv() {
+			Ok(result) => result,
+			Err(_) => GenErr!("nonvolatile: background writer for state \"{}\" has stopped", self.inner.name),
+		}
+	}
+
+
+	///Close this handle. If it's the last one, close the state like `State::close` once every
+	///pending change is written, and return any error along the way. Otherwise, the state 
+	///stays open for the other handles, and this is the same as `flush`.
+	///
+	///### Example
+	///
+	///```rust
+	///let state = State::load_else_create("my_server")?.into_shared();
+	///state.set("last_job", 17)?;
+	///state.close()?;
+	///```
+	pub fn close(self) -> Result<()> {
+		match Arc::try_unwrap(self.inner) {
+			Ok(mut shared) => shared.close(),
+			Err(inner) => SharedState { inner }.flush(),
+		}
+	}
+}
+
+
+impl Shared {
+
+	fn close(&mut self) -> Result<()> {
+		//hanging up makes the writer finish any pending write, then close the state
+		if let Ok(mut writer) = self.writer.lock() {
+			let _ = writer.take();
+		}
+		let handle = match self.handle.lock() {
+			Ok(mut handle) => handle.take(),
+			Err(poisoned) => poisoned.into_inner().take(),
+		};
+		match handle.map(|handle| handle.join()) {
+			Some(Ok(result)) => result,
+			Some(Err(_)) => GenErr!("nonvolatile: background writer for state \"{}\" panicked", self.name),
+			None => Ok(()),
+		}
+	}
+}
+
+
+impl Drop for Shared {
+	fn drop(&mut self) {
+		let _ = self.close();
+	}
+}
@@ -0,0 +1,243 @@
+/*
+SQLite storage backend for nonvolatile states
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::ErrorKind;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use generic_error::{Result, GenErr, GenericError};
+
+use crate::{lock, permissions, Backend};
+
+
+const SCHEMA: &str = "
+	CREATE TABLE IF NOT EXISTS states (
+		name TEXT PRIMARY KEY,
+		manifest BLOB NOT NULL
+	);
+	CREATE TABLE IF NOT EXISTS items (
+		state TEXT NOT NULL,
+		key TEXT NOT NULL,
+		value TEXT NOT NULL,
+		PRIMARY KEY (state, key)
+	);
+	CREATE TABLE IF NOT EXISTS locks (
+		state TEXT PRIMARY KEY,
+		owner TEXT NOT NULL
+	);
+";
+
+
+///Stores states in a single SQLite database file, with each item in its own row, so `set`
+///and `delete` only touch the row they change instead of rewriting the whole manifest. Worth
+///it for states with a lot of items. Requires the `sqlite` feature.
+///
+///Several processes can use the same database at once; each state is still locked by
+///whichever process has it open.
+///
+///To move an existing state into the database, see `StateBuilder::migrate_from`.
+///
+///### Example
+///
+///```rust
+///let backend = Arc::new(SqliteBackend::open("/srv/my_app/states.db")?);
+///let mut state = State::builder("my_state")
+///    .backend(backend)
+///    .create_if_missing(true)
+///    .open()?;
+///state.set("var", 17)?;
+///```
+pub struct SqliteBackend {
+	path: String,
+	conn: Mutex<Connection>,
+}
+
+
+impl fmt::Debug for SqliteBackend {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("SqliteBackend")
+			.field("path", &self.path)
+			.finish()
+	}
+}
+
+
+impl SqliteBackend {
+
+	///Open the database at `path`, creating it if it doesn't exist.
+	pub fn open(path: &str) -> Result<SqliteBackend> {
+		//create it ourselves, so that it gets the right permissions
+		match permissions::create_new_file(path) {
+			Ok(_) => (),
+			Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+			Err(e) => return Err(e.into()),
+		}
+		let conn = Connection::open(path)?;
+		conn.busy_timeout(Duration::from_secs(10))?;
+		conn.execute_batch(SCHEMA)?;
+		Ok(SqliteBackend {
+			path: String::from(path),
+			conn: Mutex::new(conn),
+		})
+	}
+
+
+	///The path of the database file.
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+
+	fn conn(&self) -> MutexGuard<'_, Connection> {
+		match self.conn.lock() {
+			Ok(conn) => conn,
+			Err(poisoned) => poisoned.into_inner(),
+		}
+	}
+}
+
+
+impl Backend for SqliteBackend {
+
+	fn read_manifest(&self, name: &str) -> Result<Option<Vec<u8>>> {
+		let manifest = self.conn()
+			.query_row("SELECT manifest FROM states WHERE name = ?1", params![name], |row| row.get(0))
+			.optional()?;
+		Ok(manifest)
+	}
+
+
+	fn write_manifest(&self, name: &str, data: &[u8]) -> Result<()> {
+		self.conn().execute(
+			"INSERT INTO states (name, manifest) VALUES (?1, ?2)
+				ON CONFLICT (name) DO UPDATE SET manifest = excluded.manifest",
+			params![name, data],
+		)?;
+		Ok(())
+	}
+
+
+	fn exists(&self, name: &str) -> Result<bool> {
+		let found: Option<i64> = self.conn()
+			.query_row("SELECT 1 FROM states WHERE name = ?1", params![name], |row| row.get(0))
+			.optional()?;
+		Ok(found.is_some())
+	}
+
+
+	fn stores_items(&self) -> bool {
+		true
+	}
+
+
+	fn read_items(&self, name: &str) -> Result<HashMap<String, String>> {
+		let conn = self.conn();
+		let mut statement = conn.prepare("SELECT key, value FROM items WHERE state = ?1")?;
+		let rows = statement.query_map(params![name], |row| Ok((row.get(0)?, row.get(1)?)))?;
+		let mut items = HashMap::new();
+		for row in rows {
+			let (key, value): (String, String) = row?;
+			let _ = items.insert(key, value);
+		}
+		Ok(items)
+	}
+
+
+	fn write_items(&self, name: &str, changes: &[(&str, Option<&str>)]) -> Result<()> {
+		let mut conn = self.conn();
+		let transaction = conn.transaction()?;
+		for (key, value) in changes {
+			match value {
+				Some(value) => transaction.execute(
+					"INSERT INTO items (state, key, value) VALUES (?1, ?2, ?3)
+						ON CONFLICT (state, key) DO UPDATE SET value = excluded.value",
+					params![name, key, value],
+				)?,
+				None => transaction.execute(
+					"DELETE FROM items WHERE state = ?1 AND key = ?2",
+					params![name, key],
+				)?,
+			};
+		}
+		transaction.commit()?;
+		Ok(())
+	}
+
+
+	fn replace_items(&self, name: &str, items: &HashMap<String, String>) -> Result<()> {
+		let mut conn = self.conn();
+		let transaction = conn.transaction()?;
+		transaction.execute("DELETE FROM items WHERE state = ?1", params![name])?;
+		for (key, value) in items {
+			transaction.execute(
+				"INSERT INTO items (state, key, value) VALUES (?1, ?2, ?3)",
+				params![name, key, value],
+			)?;
+		}
+		transaction.commit()?;
+		Ok(())
+	}
+
+
+	fn acquire_lock(&self, name: &str, owner: &str) -> Result<()> {
+		let mut conn = self.conn();
+		//take the database's write lock straight away, so nobody can take the lock in between
+		let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+		let holder: Option<String> = transaction
+			.query_row("SELECT owner FROM locks WHERE state = ?1", params![name], |row| row.get(0))
+			.optional()?;
+		if let Some(holder) = holder {
+			if !lock::same_owner(&holder, owner) {
+				if let Some(info) = lock::LockInfo::parse(&holder).filter(|info| info.is_alive()) {
+					return GenErr!("nonvolatile: state \"{}\" is already in use by {}", name, info);
+				}
+			}
+		}
+		transaction.execute(
+			"INSERT INTO locks (state, owner) VALUES (?1, ?2)
+				ON CONFLICT (state) DO UPDATE SET owner = excluded.owner",
+			params![name, owner],
+		)?;
+		transaction.commit()?;
+		Ok(())
+	}
+
+
+	fn release_lock(&self, name: &str, owner: &str) -> Result<()> {
+		let mut conn = self.conn();
+		let transaction = conn.transaction()?;
+		let holder: Option<String> = transaction
+			.query_row("SELECT owner FROM locks WHERE state = ?1", params![name], |row| row.get(0))
+			.optional()?;
+		if holder.map(|holder| lock::same_owner(&holder, owner)) == Some(true) {
+			transaction.execute("DELETE FROM locks WHERE state = ?1", params![name])?;
+		}
+		transaction.commit()?;
+		Ok(())
+	}
+
+
+	fn list(&self) -> Result<Vec<String>> {
+		let conn = self.conn();
+		let mut statement = conn.prepare("SELECT name FROM states ORDER BY name")?;
+		let rows = statement.query_map([], |row| row.get(0))?;
+		let mut names = Vec::new();
+		for row in rows {
+			names.push(row?);
+		}
+		Ok(names)
+	}
+
+
+	fn destroy(&self, name: &str) -> Result<()> {
+		let mut conn = self.conn();
+		let transaction = conn.transaction()?;
+		transaction.execute("DELETE FROM items WHERE state = ?1", params![name])?;
+		transaction.execute("DELETE FROM states WHERE name = ?1", params![name])?;
+		transaction.commit()?;
+		Ok(())
+	}
+}
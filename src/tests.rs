@@ -8,13 +8,13 @@ August 2019
 
 use super::*;
 use std::sync::Mutex;
-use std::fs::remove_dir_all;
+use std::fs::{remove_dir_all, read, OpenOptions};
 use lazy_static::lazy_static;
 
 
 fn destroy_state(name: &str) {
 	println!("Destroying any pre-existing state for {}", name);
-	State::destroy_state(name);
+	State::destroy_state(name, false).unwrap();
 	println!("Done");
 }
 
@@ -43,6 +43,31 @@ fn setup_env() -> String {
 }
 
 
+///A scratch directory for a test, removed along with everything in it when it's dropped.
+struct TempDir {
+	path: String,
+}
+
+
+impl TempDir {
+	fn new(name: &str) -> TempDir {
+		let path = env::temp_dir().join(format!("rust_nonvolatile_{}.{}.{:08x}", name, process::id(), random::<u32>()));
+		let path = path.to_string_lossy().to_string();
+		permissions::create_dir(&path).unwrap();
+		TempDir {
+			path,
+		}
+	}
+}
+
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = remove_dir_all(&self.path);
+	}
+}
+
+
 #[test]
 #[should_panic]
 fn test_destroy_state() {
@@ -63,6 +88,41 @@ fn test_destroy_state() {
 }
 
 
+#[test]
+fn test_destroy_locked_state() {
+	let dir = TempDir::new("test_destroy_locked");
+	let s = State::new_from("test_destroy_locked", &dir.path).unwrap();
+	assert!(State::destroy_state_from("test_destroy_locked", &dir.path, false).is_err());
+	assert!(FsBackend::new(&dir.path).exists("test_destroy_locked").unwrap());
+	State::destroy_state_from("test_destroy_locked", &dir.path, true).unwrap();
+	assert!(!FsBackend::new(&dir.path).exists("test_destroy_locked").unwrap());
+	drop(s);
+	
+	//only nonvolatile states are deleted
+	permissions::create_dir(&format!("{}/not_a_state", dir.path)).unwrap();
+	assert!(State::destroy_state_from("not_a_state", &dir.path, true).is_err());
+	assert!(Path::new(&format!("{}/not_a_state", dir.path)).exists());
+	State::destroy_state_from("never_existed", &dir.path, false).unwrap();
+}
+
+
+#[test]
+fn test_close() {
+	let dir = TempDir::new("test_close");
+	let mut s = State::new_from("test_close", &dir.path).unwrap();
+	s.set("var", 1).unwrap();
+	s.close().unwrap();
+	let s = State::load_from("test_close", &dir.path).unwrap();
+	assert_eq!(s.get::<u32>("var"), Some(1));
+	
+	//closing a temporary state deletes it
+	let s = State::temporary("test_close_temporary").unwrap();
+	let path = s.path.clone();
+	s.close().unwrap();
+	assert!(!Path::new(&path).exists());
+}
+
+
 #[test]
 #[should_panic]
 fn test_dir_locking() {
@@ -105,8 +165,8 @@ fn test_name_abuse3() {
 fn test_custom_storage_dir() {
 	let name = setup_env();
 	let custom_dir = "./~rust_nonvolatile_test_tmp_dir";
-	State::destroy_state_from(&name, custom_dir);
-	State::destroy_state(&name);
+	State::destroy_state_from(&name, custom_dir, false).unwrap();
+	State::destroy_state(&name, false).unwrap();
 	let mut s = State::new_from(&name, custom_dir).unwrap();
 	test_state(&mut s);
 	s.set("check persistence", true).unwrap();
@@ -213,3 +273,902 @@ fn test_example() {
 	assert_eq!(state.get::<String>("var").unwrap(), "some value");
 }
 
+
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encrypted_state() {
+	let name = setup_env();
+	let key = EncryptionKey::Passphrase(String::from("correct horse battery staple"));
+	{
+		let mut s = State::new_encrypted(&name, &key).unwrap();
+		test_state(&mut s);
+		s.set("api_token", "hunter2").unwrap();
+	}
+	
+	let manifest = read(format!("{}/{}/.manifest", get_storage_dir().unwrap(), name)).unwrap();
+	assert!(manifest.starts_with(ENCRYPTED_MAGIC));
+	assert!(!String::from_utf8_lossy(&manifest).contains("hunter2"));
+	
+	assert!(State::load(&name).is_err());
+	let wrong = EncryptionKey::Passphrase(String::from("tr0ub4dor&3"));
+	assert!(State::load_encrypted(&name, &wrong).is_err());
+	assert!(State::load_else_create_encrypted(&name, &wrong).is_err());
+	
+	let s = State::load_encrypted(&name, &key).unwrap();
+	assert_eq!(s.get::<String>("api_token"), Some(String::from("hunter2")));
+}
+
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encrypted_raw_key() {
+	let name = setup_env();
+	let key = EncryptionKey::Raw([7u8; 32]);
+	{
+		let mut s = State::load_else_create_encrypted(&name, &key).unwrap();
+		s.set("answer", 42).unwrap();
+	}
+	
+	let passphrase = EncryptionKey::Passphrase(String::from("seven"));
+	assert!(State::load_encrypted(&name, &passphrase).is_err());
+	assert!(State::load_encrypted(&name, &EncryptionKey::Raw([8u8; 32])).is_err());
+	
+	let s = State::load_else_create_encrypted(&name, &key).unwrap();
+	assert_eq!(s.get::<u32>("answer"), Some(42));
+}
+
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_secrets() {
+	let name = setup_env();
+	let keyfile = PathBuf::from(format!("./~rust_nonvolatile_test_{}.key", name));
+	let _ = remove_file(&keyfile);
+	{
+		let mut s = State::new(&name).unwrap();
+		assert!(s.set_secret("api_token", "hunter2").is_err());
+		s.set_secret_key(&EncryptionKey::Keyfile(keyfile.clone())).unwrap();
+		s.set_secret("api_token", "hunter2").unwrap();
+		s.set("theme", "dark").unwrap();
+		
+		assert!(s.has("api_token"));
+		assert_eq!(s.get::<String>("api_token"), None);
+		assert_eq!(s.get_secret::<String>("api_token"), Some(String::from("hunter2")));
+		assert!(!format!("{:?}", s).contains("hunter2"));
+	}
+	
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::PermissionsExt;
+		assert_eq!(metadata(&keyfile).unwrap().permissions().mode() & 0o777, 0o600);
+	}
+	
+	let manifest = read_to_string(format!("{}/{}/.manifest", get_storage_dir().unwrap(), name)).unwrap();
+	assert!(!manifest.contains("hunter2"));
+	
+	let mut s = State::load(&name).unwrap();
+	assert_eq!(s.get_secret::<String>("api_token"), None);
+	assert!(s.set_secret_key(&EncryptionKey::Raw([0u8; 32])).is_err());
+	s.set_secret_key(&EncryptionKey::Keyfile(keyfile.clone())).unwrap();
+	assert_eq!(s.get_secret::<String>("api_token"), Some(String::from("hunter2")));
+	drop(s);
+	
+	//copies keep secrets encrypted, under the same key
+	let copy_name = format!("{}_copy", name);
+	State::copy(&name, &copy_name, true).unwrap();
+	let manifest = read_to_string(format!("{}/{}/.manifest", get_storage_dir().unwrap(), copy_name)).unwrap();
+	assert!(!manifest.contains("hunter2"));
+	let mut copy = State::load(&copy_name).unwrap();
+	copy.set_secret_key(&EncryptionKey::Keyfile(keyfile.clone())).unwrap();
+	assert_eq!(copy.get_secret::<String>("api_token"), Some(String::from("hunter2")));
+	drop(copy);
+	State::destroy_state(&copy_name, true).unwrap();
+	
+	let mut s = State::load(&name).unwrap();
+	s.delete("api_token").unwrap();
+	assert!(!s.has("api_token"));
+	remove_file(&keyfile).unwrap();
+}
+
+
+#[cfg(unix)]
+#[test]
+fn test_permissions() {
+	use std::os::unix::fs::PermissionsExt;
+	use std::fs::set_permissions;
+	
+	let name = setup_env();
+	let path = format!("{}/{}", get_storage_dir().unwrap(), name);
+	{
+		let mut s = State::new(&name).unwrap();
+		s.set("foo", "bar").unwrap();
+	}
+	assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o700);
+	let manifest = format!("{}/.manifest", path);
+	assert_eq!(metadata(&manifest).unwrap().permissions().mode() & 0o777, 0o600);
+	
+	set_permissions(&path, std::fs::Permissions::from_mode(0o777)).unwrap();
+	assert!(State::load(&name).is_err());
+	assert!(State::new(&name).is_err());
+	
+	set_permissions(&path, std::fs::Permissions::from_mode(0o700)).unwrap();
+	let s = State::load(&name).unwrap();
+	assert_eq!(s.get::<String>("foo"), Some(String::from("bar")));
+	drop(s);
+	
+	//states left readable by others, e.g. by older versions, are narrowed down
+	set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+	set_permissions(&manifest, std::fs::Permissions::from_mode(0o644)).unwrap();
+	let s = State::load(&name).unwrap();
+	assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o700);
+	assert_eq!(metadata(&manifest).unwrap().permissions().mode() & 0o777, 0o600);
+	drop(s);
+}
+
+
+#[test]
+fn test_resolve_storage_dir() {
+	let env = |vars: &'static [(&'static str, &'static str)]| {
+		move |name: &str| vars.iter().find(|(k, _)| *k == name).map(|(_, v)| String::from(*v))
+	};
+	let home = env(&[("HOME", "/home/me")]);
+	assert_eq!(resolve_storage_dir(StateKind::Config, Linux, home).unwrap(), "/home/me/.config/rust_nonvolatile");
+	assert_eq!(resolve_storage_dir(StateKind::Data, Linux, home).unwrap(), "/home/me/.local/share/rust_nonvolatile");
+	assert_eq!(resolve_storage_dir(StateKind::State, Linux, home).unwrap(), "/home/me/.local/state/rust_nonvolatile");
+	assert_eq!(resolve_storage_dir(StateKind::Config, MacOS, home).unwrap(), "/home/me/.local/rust_nonvolatile");
+	
+	let xdg = env(&[("HOME", "/home/me"), ("XDG_CONFIG_HOME", "/xdg/config"), ("XDG_DATA_HOME", "relative/data"), ("XDG_STATE_HOME", "")]);
+	assert_eq!(resolve_storage_dir(StateKind::Config, Linux, xdg).unwrap(), "/xdg/config/rust_nonvolatile");
+	assert_eq!(resolve_storage_dir(StateKind::Data, Linux, xdg).unwrap(), "/home/me/.local/share/rust_nonvolatile");
+	assert_eq!(resolve_storage_dir(StateKind::State, Linux, xdg).unwrap(), "/home/me/.local/state/rust_nonvolatile");
+	
+	let overridden = env(&[("HOME", "/home/me"), ("XDG_CONFIG_HOME", "/xdg/config"), ("NONVOLATILE_DIR", "/srv/settings")]);
+	assert_eq!(resolve_storage_dir(StateKind::Config, Linux, overridden).unwrap(), "/srv/settings");
+	assert_eq!(resolve_storage_dir(StateKind::Config, Windows, overridden).unwrap(), "/srv/settings");
+}
+
+
+#[test]
+fn test_legacy_migration() {
+	let name = setup_env();
+	let legacy_dir = match legacy_storage_dir() {
+		Some(dir) => dir,
+		None => return,
+	};
+	if canonicalize_path(&legacy_dir) == canonicalize_path(get_storage_dir().unwrap()) {
+		return;
+	}
+	State::destroy_state_from(&name, &legacy_dir, false).unwrap();
+	{
+		let mut s = State::new_from(&name, &legacy_dir).unwrap();
+		s.set("migrated", true).unwrap();
+	}
+	
+	let mut s = State::load(&name).unwrap();
+	assert_eq!(s.get("migrated"), Some(true));
+	assert!(metadata(format!("{}/{}", legacy_dir, name)).is_err());
+	s.set("written after migration", true).unwrap();
+	drop(s);
+	
+	let s = State::load(&name).unwrap();
+	assert_eq!(s.get("written after migration"), Some(true));
+}
+
+
+#[test]
+fn test_builder() {
+	let name = setup_env();
+	assert!(State::builder(&name).open().is_err());
+	assert!(State::builder(&name).create_if_missing(true).read_only(true).open().is_err());
+	
+	let mut s = State::builder(&name).create_if_missing(true).format(Format::Json).open().unwrap();
+	s.set("foo", "bar").unwrap();
+	let manifest = read_to_string(format!("{}/{}/.manifest", get_storage_dir().unwrap(), name)).unwrap();
+	assert!(manifest.starts_with("{"));
+	
+	let mut reader = State::builder(&name).read_only(true).open().unwrap();
+	assert_eq!(reader.get::<String>("foo"), Some(String::from("bar")));
+	assert!(reader.set("foo", "baz").is_err());
+	drop(reader);
+	
+	//dropping the read-only state must not have released the writer's lock
+	assert!(State::load(&name).is_err());
+	drop(s);
+	
+	let s = State::load(&name).unwrap();
+	assert_eq!(s.get::<String>("foo"), Some(String::from("bar")));
+}
+
+
+#[test]
+fn test_lock_timeout() {
+	let name = setup_env();
+	let s = State::new(&name).unwrap();
+	let releaser = thread::spawn(move || {
+		thread::sleep(time::Duration::from_millis(200));
+		drop(s);
+	});
+	
+	let s = State::builder(&name).lock_timeout(time::Duration::from_secs(10)).open();
+	releaser.join().unwrap();
+	assert!(s.is_ok());
+}
+
+
+#[test]
+fn test_shared_state() {
+	fn assert_shareable<T: Clone + Send + Sync>() {}
+	assert_shareable::<SharedState>();
+	
+	let name = setup_env();
+	let shared = State::new(&name).unwrap().into_shared();
+	let workers: Vec<_> = (0..4u32).map(|i| {
+		let shared = shared.clone();
+		thread::spawn(move || {
+			for j in 0..25u32 {
+				shared.set(&format!("worker {} item {}", i, j), i * j).unwrap();
+				assert_eq!(shared.get::<u32>(&format!("worker {} item {}", i, j)), Some(i * j));
+			}
+		})
+	}).collect();
+	for worker in workers {
+		worker.join().unwrap();
+	}
+	shared.delete("worker 0 item 0").unwrap();
+	shared.flush().unwrap();
+	assert!(!shared.has("worker 0 item 0"));
+	
+	//still locked while any handle is alive
+	let other = shared.clone();
+	other.set("closed", true).unwrap();
+	other.close().unwrap();
+	assert!(State::load(&name).is_err());
+	shared.close().unwrap();
+	
+	let s = State::load(&name).unwrap();
+	assert_eq!(s.get::<u32>("worker 3 item 24"), Some(72));
+	assert_eq!(s.get::<bool>("closed"), Some(true));
+	assert!(!s.has("worker 0 item 0"));
+}
+
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_async_state() {
+	let name = setup_env();
+	{
+		let mut s = AsyncState::new(&name).await.unwrap();
+		s.set("foo", "bar").await.unwrap();
+		s.set("count", 3).await.unwrap();
+		s.delete("count").await.unwrap();
+		s.flush().await.unwrap();
+		assert_eq!(s.get::<String>("foo"), Some(String::from("bar")));
+		assert!(AsyncState::load(&name).await.is_err());
+		
+		//a write whose future is dropped still finishes, and leaves the state usable
+		let mut cancelled = Box::pin(s.set("cancelled", 1));
+		let polled = std::future::poll_fn(|cx| std::task::Poll::Ready(std::future::Future::poll(cancelled.as_mut(), cx))).await;
+		assert!(polled.is_pending());
+		drop(cancelled);
+		assert_eq!(s.get::<String>("foo"), Some(String::from("bar")));
+		s.set("after", 2).await.unwrap();
+		assert!(AsyncState::load(&name).await.is_err());
+		
+		let waiting = AsyncState::open(State::builder(&name).lock_timeout(time::Duration::from_secs(10)));
+		let (loaded, _) = tokio::join!(waiting, async move {
+			tokio::time::sleep(time::Duration::from_millis(200)).await;
+			drop(s);
+		});
+		let loaded = loaded.unwrap();
+		assert!(!loaded.has("count"));
+		assert_eq!(loaded.get::<u32>("cancelled"), Some(1));
+		assert_eq!(loaded.get::<u32>("after"), Some(2));
+	}
+	
+	let s = State::load(&name).unwrap();
+	assert_eq!(s.get::<String>("foo"), Some(String::from("bar")));
+	drop(s);
+	
+	//in shared mode, writes keep what others wrote in the meantime
+	let mut b = State::builder(&name).shared(true).open().unwrap();
+	let mut a = AsyncState::open(State::builder(&name).shared(true)).await.unwrap();
+	b.set("from b", 1).unwrap();
+	a.set("from a", 2).await.unwrap();
+	drop(a);
+	drop(b);
+	let s = State::load(&name).unwrap();
+	assert_eq!(s.get::<u32>("from b"), Some(1));
+	assert_eq!(s.get::<u32>("from a"), Some(2));
+}
+
+
+#[test]
+fn test_shared_mode() {
+	let name = setup_env();
+	drop(State::new(&name).unwrap());
+	
+	let mut a = State::builder(&name).shared(true).open().unwrap();
+	let mut b = State::builder(&name).shared(true).open().unwrap();
+	assert!(State::load(&name).is_err());
+	
+	a.set("from a", 1).unwrap();
+	b.set("from b", 2).unwrap();
+	assert_eq!(b.get::<u32>("from a"), Some(1));
+	assert_eq!(a.get::<u32>("from b"), None);
+	a.reload().unwrap();
+	assert_eq!(a.get::<u32>("from b"), Some(2));
+	
+	let writers: Vec<_> = (0..4u32).map(|i| {
+		let name = name.clone();
+		thread::spawn(move || {
+			let mut s = State::builder(&name).shared(true).open().unwrap();
+			for j in 0..20u32 {
+				s.set(&format!("writer {} item {}", i, j), j).unwrap();
+			}
+		})
+	}).collect();
+	for writer in writers {
+		writer.join().unwrap();
+	}
+	b.delete("from a").unwrap();
+	
+	//so do `SharedState`'s
+	let shared = State::builder(&name).shared(true).open().unwrap().into_shared();
+	b.set("from b again", 3).unwrap();
+	shared.set("from shared", 4).unwrap();
+	shared.flush().unwrap();
+	drop(shared);
+	drop(a);
+	drop(b);
+	
+	let s = State::load(&name).unwrap();
+	assert!(!s.has("from a"));
+	assert_eq!(s.get::<u32>("from b"), Some(2));
+	assert_eq!(s.get::<u32>("from b again"), Some(3));
+	assert_eq!(s.get::<u32>("from shared"), Some(4));
+	for i in 0..4u32 {
+		for j in 0..20u32 {
+			assert_eq!(s.get::<u32>(&format!("writer {} item {}", i, j)), Some(j));
+		}
+	}
+	assert!(State::builder(&name).shared(true).open().is_err());
+}
+
+
+#[test]
+fn test_lock_owner_liveness() {
+	let my_id = get_state_id().unwrap();
+	let parts: Vec<&str> = my_id.split('\n').collect();
+	let other_id = format!("{}\n{}\n{}\n{}", parts[0], 0, parts[2], parts[3]);
+	assert!(matches!(match_state_id(&my_id, &my_id), WhoOwns::Me));
+	assert!(matches!(match_state_id("", &other_id), WhoOwns::Other));
+	//lockfiles from older versions have no start time
+	let old_id = format!("{}\n{}\n{}", parts[0], 0, parts[2]);
+	assert!(matches!(match_state_id("", &old_id), WhoOwns::Other));
+	//a different executable means the PID has been reused
+	let reused_id = format!("{}\n{}\n{}\n{}", parts[0], 0, "/nonexistent/exe", parts[3]);
+	assert!(matches!(match_state_id("", &reused_id), WhoOwns::Nobody));
+	#[cfg(target_os = "linux")]
+	{
+		let start_time: u64 = parts[3].parse().unwrap();
+		let reused_id = format!("{}\n{}\n{}\n{}", parts[0], 0, parts[2], start_time + 1);
+		assert!(matches!(match_state_id("", &reused_id), WhoOwns::Nobody));
+	}
+	let dead_id = format!("{}\n{}\n{}\n", 4000000000u32, 0, parts[2]);
+	assert!(matches!(match_state_id("", &dead_id), WhoOwns::Nobody));
+}
+
+
+#[test]
+fn test_force_unlock() {
+	let name = setup_env();
+	assert_eq!(lock_info(&name).unwrap(), None);
+	let mut s = State::new(&name).unwrap();
+	
+	let info = lock_info(&name).unwrap().unwrap();
+	assert_eq!(info.pid, process::id());
+	assert!(info.acquired.is_some());
+	assert!(info.hostname.is_some());
+	assert!(info.is_alive());
+	
+	//the owner is still running
+	assert!(State::force_unlock(&name, false).is_err());
+	assert!(State::load_steal(&name, false).is_err());
+	
+	let s2 = State::load_steal(&name, true).unwrap();
+	//the old owner must not release the lock it lost
+	let _ = s.set("var", 1);
+	drop(s);
+	assert!(lock_info(&name).unwrap().is_some());
+	drop(s2);
+	assert_eq!(lock_info(&name).unwrap(), None);
+}
+
+
+#[test]
+fn test_remote_lock_owner() {
+	let exe = liveness::current_exe();
+	//a PID that doesn't exist here might well exist on the other host
+	let remote_id = format!("4000000000\n0\n{}\n\n\nsome-other-host\n", exe);
+	assert!(matches!(match_state_id("", &remote_id), WhoOwns::Other));
+	let expired_id = format!("4000000000\n0\n{}\n\n\nsome-other-host\n\n1000000", exe);
+	assert!(matches!(match_state_id("", &expired_id), WhoOwns::Nobody));
+	
+	//same host, but written before the last reboot
+	#[cfg(target_os = "linux")]
+	{
+		let id = format!("{}\n0\n{}\n\n\n{}\nsome-old-boot-id", process::id(), exe, whoami::hostname());
+		assert!(matches!(match_state_id("", &id), WhoOwns::Nobody));
+	}
+	
+	//renewing the lease doesn't change who owns the lock
+	let my_id = get_state_id().unwrap();
+	assert!(lock::same_owner(&my_id, &format!("{}\n{}", my_id, 2000000000)));
+	assert!(!lock::same_owner(&my_id, &get_state_id().unwrap()));
+}
+
+
+#[test]
+fn test_lease() {
+	let name = setup_env();
+	let lease = time::Duration::from_millis(300);
+	let s = State::builder(&name).create_if_missing(true).lease(lease).open().unwrap();
+	let expires = lock_info(&name).unwrap().unwrap().lease_expires.unwrap();
+	
+	//the lease keeps getting renewed while the state is open
+	thread::sleep(time::Duration::from_millis(600));
+	let info = lock_info(&name).unwrap().unwrap();
+	assert!(info.lease_expires.unwrap() > expires);
+	assert!(info.is_alive());
+	assert!(State::load(&name).is_err());
+	drop(s);
+	assert_eq!(lock_info(&name).unwrap(), None);
+	
+	//a lease that wasn't renewed can be taken over, even though its owner is still running
+	let lockfile_path = format!("{}/{}/{}", get_storage_dir().unwrap(), name, LOCKFILE_NAME);
+	let expired_id = format!("{}\n{}", get_state_id().unwrap(), 1000000);
+	std::fs::write(&lockfile_path, &expired_id).unwrap();
+	assert!(State::load(&name).is_ok());
+	
+	//once the lock is taken over, the old owner can't write anymore
+	let mut old = State::builder(&name).lease(time::Duration::from_secs(10)).open().unwrap();
+	old.set("var", 1).unwrap();
+	State::force_unlock(&name, true).unwrap();
+	let mut new = State::load(&name).unwrap();
+	new.set("var", 2).unwrap();
+	assert!(old.set("var", 3).is_err());
+	drop(old);
+	drop(new);
+	assert_eq!(State::load(&name).unwrap().get::<u32>("var"), Some(2));
+}
+
+
+#[test]
+fn test_in_memory_and_temporary() {
+	let mut s = State::in_memory("test_in_memory");
+	test_state(&mut s);
+	let mut s2 = State::in_memory("test_in_memory");
+	assert!(!s2.has("test_var"));
+	s2.reload().unwrap();
+	test_state(&mut s2);
+	
+	let mut s = State::temporary("test_temporary").unwrap();
+	test_state(&mut s);
+	let path = s.path.clone();
+	assert!(read(format!("{}/.manifest", path)).is_ok());
+	let s2 = State::temporary("test_temporary").unwrap();
+	assert_ne!(s2.path, path);
+	drop(s);
+	assert!(metadata(&path).is_err());
+}
+
+
+#[test]
+fn test_backend() {
+	let backend: Arc<dyn Backend> = Arc::new(MemoryBackend::new());
+	let mut s = State::builder("test_backend").backend(backend.clone()).create_if_missing(true).open().unwrap();
+	test_state(&mut s);
+	assert!(State::builder("test_backend").backend(backend.clone()).open().is_err());
+	assert!(State::builder("test_backend").backend(backend.clone()).shared(true).open().is_err());
+	drop(s);
+	
+	let s = State::builder("test_backend").backend(backend.clone()).open().unwrap();
+	assert_eq!(s.get("test_var"), Some(String::from("foo")));
+	assert_eq!(backend.list().unwrap(), vec![String::from("test_backend")]);
+	drop(s);
+	backend.destroy("test_backend").unwrap();
+	assert!(State::builder("test_backend").backend(backend).open().is_err());
+	
+	let s = State::temporary("test_fs_backend").unwrap();
+	let fs = FsBackend::new(Path::new(&s.path).parent().unwrap().to_str().unwrap());
+	assert_eq!(fs.list().unwrap(), vec![String::from("test_fs_backend")]);
+	assert!(fs.exists("test_fs_backend").unwrap());
+	assert!(fs.acquire_lock("test_fs_backend", &get_state_id().unwrap()).is_err());
+}
+
+
+#[test]
+fn test_write_ahead_log() {
+	let dir = TempDir::new("test_wal");
+	let open = |log| State::builder("test_wal").storage_dir(&dir.path).write_ahead_log(log).create_if_missing(true).open();
+	let mut s = open(false).unwrap();
+	s.set("var", 1).unwrap();
+	let path = s.path.clone();
+	drop(s);
+	
+	//switching an existing state over moves its items out of the manifest
+	let mut s = open(true).unwrap();
+	assert_eq!(s.get::<u32>("var"), Some(1));
+	assert!(!read_to_string(format!("{}/.manifest", path)).unwrap().contains("var"));
+	let big = "x".repeat(10_000);
+	for i in 0..6 {
+		s.set(&format!("big_{}", i), &big).unwrap();
+	}
+	assert!(Path::new(&format!("{}/.log", path)).exists());
+	//the log is now over twice the size of the snapshot, so it gets compacted
+	s.set("big_6", &big).unwrap();
+	assert!(!Path::new(&format!("{}/.log", path)).exists());
+	s.set("var", 2).unwrap();
+	s.delete("big_0").unwrap();
+	drop(s);
+	
+	//a record cut off by a crash is ignored
+	let mut log = OpenOptions::new().append(true).open(format!("{}/.log", path)).unwrap();
+	log.write_all(b"[\"var\",\"3").unwrap();
+	drop(log);
+	let mut s = State::load_from("test_wal", &dir.path).unwrap();
+	assert_eq!(s.get::<u32>("var"), Some(2));
+	assert_eq!(s.get::<String>("big_0"), None);
+	assert_eq!(s.get::<String>("big_6"), Some(big));
+	
+	//and cut off before the next record is written
+	s.set("b", 2).unwrap();
+	drop(s);
+	let mut s = State::load_from("test_wal", &dir.path).unwrap();
+	assert_eq!(s.get::<u32>("b"), Some(2));
+	s.set("c", 3).unwrap();
+	drop(s);
+	let s = State::load_from("test_wal", &dir.path).unwrap();
+	assert_eq!(s.get::<u32>("b"), Some(2));
+	assert_eq!(s.get::<u32>("c"), Some(3));
+	assert_eq!(s.get::<u32>("var"), Some(2));
+}
+
+
+#[test]
+fn test_blobs() {
+	let dir = TempDir::new("test_blobs");
+	let open = || State::builder("test_blobs").storage_dir(&dir.path).blob_threshold(1000).create_if_missing(true).open();
+	let mut s = open().unwrap();
+	let big = "x".repeat(5000);
+	s.set("big", &big).unwrap();
+	s.set("copy", &big).unwrap();
+	s.set("small", 1).unwrap();
+	let blob_dir = format!("{}/.blobs", s.path);
+	let count_blobs = || read_dir(&blob_dir).map(|entries| entries.count()).unwrap_or(0);
+	assert_eq!(count_blobs(), 1);
+	assert!(!read_to_string(format!("{}/.manifest", s.path)).unwrap().contains("xxxx"));
+	assert_eq!(s.get::<String>("big"), Some(big.clone()));
+	assert!(s.has("big"));
+	
+	//the blob is only removed once nothing refers to it
+	s.delete("big").unwrap();
+	assert_eq!(count_blobs(), 1);
+	s.set("copy", 2).unwrap();
+	assert_eq!(count_blobs(), 0);
+	s.set("big", &big).unwrap();
+	drop(s);
+	
+	//blobs nobody refers to are collected on load
+	let mut orphan = permissions::create_file(&format!("{}/orphan", blob_dir)).unwrap();
+	orphan.write_all(b"orphan").unwrap();
+	drop(orphan);
+	let s = open().unwrap();
+	assert_eq!(count_blobs(), 1);
+	assert_eq!(s.get::<String>("big"), Some(big));
+	assert_eq!(s.get::<u32>("copy"), Some(2));
+	drop(s);
+	
+	//blobs can't be looked for outside the blob directory
+	let manifest_path = format!("{}/test_blobs/.manifest", dir.path);
+	let manifest = read_to_string(&manifest_path).unwrap();
+	let manifest = Regex::new("[0-9a-f]{64}").unwrap().replace(&manifest, "../../outside");
+	permissions::create_file(&manifest_path).unwrap().write_all(manifest.as_bytes()).unwrap();
+	assert!(open().unwrap_err().msg.contains("isn't a digest"));
+}
+
+
+#[test]
+fn test_lazy_load() {
+	let dir = TempDir::new("test_lazy");
+	let builder = || State::builder("test_lazy").storage_dir(&dir.path);
+	let mut s = builder().format(Format::Json).create_if_missing(true).open().unwrap();
+	s.set("number", 17).unwrap();
+	s.set("control", "\u{1}").unwrap();
+	drop(s);
+	
+	let mut s = builder().format(Format::Json).lazy(true).open().unwrap();
+	assert!(s.lazy.is_some());
+	assert!(s.items.is_empty());
+	assert!(s.has("number"));
+	assert_eq!(s.get::<u32>("number"), Some(17));
+	assert_eq!(s.get::<String>("control"), Some(String::from("\u{1}")));
+	//the first change loads everything
+	s.delete("number").unwrap();
+	assert!(s.lazy.is_none());
+	assert_eq!(s.get::<String>("control"), Some(String::from("\u{1}")));
+	drop(s);
+	
+	#[cfg(feature = "mmap")]
+	{
+		let s = builder().mmap(true).open().unwrap();
+		assert!(s.lazy.is_some());
+		assert!(!s.has("number"));
+		assert_eq!(s.get::<String>("control"), Some(String::from("\u{1}")));
+		drop(s);
+		
+		//editable manifests are edited in place, so they're never mapped
+		assert!(builder().mmap(true).format(Format::Editable).open().is_err());
+		builder().format(Format::Editable).open().unwrap().set("number", 18).unwrap();
+		let s = builder().mmap(true).open().unwrap();
+		assert!(s.lazy.is_none());
+		assert_eq!(s.get::<String>("control"), Some(String::from("\u{1}")));
+	}
+	
+	//YAML manifests are just loaded up front
+	let mut s = builder().overwrite(true).open().unwrap();
+	s.set("number", 17).unwrap();
+	drop(s);
+	let s = builder().lazy(true).open().unwrap();
+	assert!(s.lazy.is_none());
+	assert_eq!(s.get::<u32>("number"), Some(17));
+}
+
+
+#[test]
+fn test_layered_state() {
+	let dir = TempDir::new("test_layered");
+	let user_dir = format!("{}/user", dir.path);
+	let system_dir = format!("{}/system", dir.path);
+	let mut system = State::builder("test_layered").storage_dir(&system_dir).create_if_missing(true).open().unwrap();
+	system.set("theme", "blue").unwrap();
+	system.set("retries", 3).unwrap();
+	drop(system);
+	
+	let mut s = LayeredState::open_from("test_layered", &user_dir, &system_dir).unwrap();
+	s.set_default("theme", "light").unwrap();
+	s.set_default("font", "mono").unwrap();
+	assert_eq!(s.get_with_layer::<String>("theme"), Some((String::from("blue"), Layer::System)));
+	assert_eq!(s.get_with_layer::<String>("font"), Some((String::from("mono"), Layer::Default)));
+	s.set("theme", "dark").unwrap();
+	assert_eq!(s.get_with_layer::<String>("theme"), Some((String::from("dark"), Layer::User)));
+	env::set_var("TEST_LAYERED_RETRIES", "5");
+	assert_eq!(s.get_with_layer::<u32>("retries"), Some((5, Layer::Env)));
+	assert_eq!(s.layer_of("retries"), Some(Layer::Env));
+	//`layer_of` only checks that a value is there, not that it has the type being asked for
+	env::set_var("TEST_LAYERED_RETRIES", "lots");
+	assert_eq!(s.get_with_layer::<u32>("retries"), Some((3, Layer::System)));
+	assert_eq!(s.layer_of("retries"), Some(Layer::Env));
+	env::remove_var("TEST_LAYERED_RETRIES");
+	assert_eq!(s.get_with_layer::<u32>("retries"), Some((3, Layer::System)));
+	assert_eq!(s.layer_of("missing"), None);
+	
+	//only the user's layer is written to
+	s.delete("theme").unwrap();
+	assert_eq!(s.get_with_layer::<String>("theme"), Some((String::from("blue"), Layer::System)));
+	assert!(s.system().unwrap().read_only);
+	assert!(!s.user().has("font"));
+	drop(s);
+	
+	//the system layer normally belongs to root, not the user
+	#[cfg(unix)]
+	{
+		if is_root() {
+			let system_path = format!("{}/test_layered", system_dir);
+			std::os::unix::fs::chown(&system_path, Some(1234), None).unwrap();
+			let s = LayeredState::open_from("test_layered", &user_dir, &system_dir).unwrap();
+			assert_eq!(s.get_with_layer::<u32>("retries"), Some((3, Layer::System)));
+			assert!(State::load_from("test_layered", &system_dir).is_err());
+		}
+	}
+}
+
+
+#[test]
+fn test_import_file() {
+	let mut s = State::temporary("test_import").unwrap();
+	let write = |file: &str, text: &str| {
+		let path = format!("{}/{}", s.path, file);
+		permissions::create_file(&path).unwrap().write_all(text.as_bytes()).unwrap();
+		path
+	};
+	let toml = write("config.toml", "name = \"app\"\n[server]\nport = 8080\nhosts = [\"a\", \"b\"]\n");
+	let ini = write("config.ini", "; comment\nname = other\n[server]\nport = 8080\ndebug = true\n");
+	let env = write(".env", "# comment\nexport TOKEN=\"abc def\"\nRETRIES=3\n");
+	assert_eq!(ImportFormat::from_path(&toml), Some(ImportFormat::Toml));
+	assert_eq!(ImportFormat::from_path(&env), Some(ImportFormat::Env));
+	
+	let report = s.import_file(&toml, ImportFormat::Toml, MergeMode::Fail).unwrap();
+	assert_eq!(report.imported.len(), 3);
+	assert!(report.conflicts.is_empty());
+	assert_eq!(s.get::<u16>("server.port"), Some(8080));
+	assert_eq!(s.get::<Vec<String>>("server.hosts"), Some(vec![String::from("a"), String::from("b")]));
+	
+	//`server.port` is the same, so only `name` conflicts
+	assert!(s.import_file(&ini, ImportFormat::Ini, MergeMode::Fail).is_err());
+	assert_eq!(s.get::<bool>("server.debug"), None);
+	let report = s.import_file(&ini, ImportFormat::Ini, MergeMode::KeepExisting).unwrap();
+	assert_eq!(report.conflicts, vec![String::from("name")]);
+	assert_eq!(s.get::<String>("name"), Some(String::from("app")));
+	assert_eq!(s.get::<bool>("server.debug"), Some(true));
+	s.import_file(&ini, ImportFormat::Ini, MergeMode::Overwrite).unwrap();
+	assert_eq!(s.get::<String>("name"), Some(String::from("other")));
+	
+	s.import_file(&env, ImportFormat::Env, MergeMode::Fail).unwrap();
+	assert_eq!(s.get::<String>("TOKEN"), Some(String::from("abc def")));
+	assert_eq!(s.get::<u32>("RETRIES"), Some(3));
+}
+
+
+#[test]
+fn test_editable_manifest() {
+	let dir = TempDir::new("test_editable");
+	let mut s = State::builder("test_editable").storage_dir(&dir.path).format(Format::Editable).create_if_missing(true).open().unwrap();
+	let path = s.path.clone();
+	s.set("theme", "dark").unwrap();
+	s.set("server", [("port", 8080)].iter().cloned().collect::<HashMap<_, _>>()).unwrap();
+	let manifest = read_to_string(format!("{}/.manifest", path)).unwrap();
+	assert!(manifest.contains("\ntheme: dark\n"));
+	assert!(manifest.contains("\nserver:\n  port: 8080\n"));
+	
+	//edit it by hand, adding a comment
+	let edited = manifest.replace("theme: dark\n", "# the theme\ntheme: light   # was dark\nretries: 3\n");
+	permissions::create_file(&format!("{}/.manifest", path)).unwrap().write_all(edited.as_bytes()).unwrap();
+	s.reload().unwrap();
+	assert_eq!(s.get::<String>("theme"), Some(String::from("light")));
+	assert_eq!(s.get::<u32>("retries"), Some(3));
+	
+	//unchanged entries and comments are kept
+	s.set("retries", 4).unwrap();
+	let manifest = read_to_string(format!("{}/.manifest", path)).unwrap();
+	assert!(manifest.contains("# the theme\ntheme: light   # was dark\nretries: 4\n"));
+	
+	//blank lines inside a hand-written block scalar belong to it
+	let edited = format!("{}motd: |\n  hello\n\n  world\n\n# after motd\n", manifest);
+	permissions::create_file(&format!("{}/.manifest", path)).unwrap().write_all(edited.as_bytes()).unwrap();
+	s.reload().unwrap();
+	s.set("b", 2).unwrap();
+	s.reload().unwrap();
+	assert_eq!(s.get::<String>("motd"), Some(String::from("hello\n\nworld\n")));
+	assert_eq!(s.get::<u32>("b"), Some(2));
+	let manifest = read_to_string(format!("{}/.manifest", path)).unwrap();
+	assert!(manifest.contains("motd: |\n  hello\n\n  world\n\n# after motd\n"), "{}", manifest);
+	
+	let broken = manifest.replace("retries: 4", "retries: [4");
+	permissions::create_file(&format!("{}/.manifest", path)).unwrap().write_all(broken.as_bytes()).unwrap();
+	let e = s.reload().unwrap_err();
+	assert!(e.msg.contains("line"), "{}", e.msg);
+	assert_eq!(s.get::<u32>("retries"), Some(4));
+}
+
+
+#[test]
+fn test_manifest_migration() {
+	let dir = TempDir::new("test_migration");
+	let path = format!("{}/test_migration", dir.path);
+	permissions::create_dir(&path).unwrap();
+	//an old manifest, as written from somewhere else
+	let old = "---\nname: test_migration\npath: /somewhere/else/test_migration\nidentifier: \"1234\"\n\
+		lockfile_path: /somewhere/else/test_migration/lock_1234\nmanifest_path: /somewhere/else/test_migration/.manifest\n\
+		tmp_manifest_path: /somewhere/else/test_migration/.manifest_tmp\nitems:\n  var: \"---\\n3\\n\"\nsecrets: {}\nsecret_salt: ~\n";
+	permissions::create_file(&format!("{}/.manifest", path)).unwrap().write_all(old.as_bytes()).unwrap();
+	
+	let mut s = State::load_from("test_migration", &dir.path).unwrap();
+	assert_eq!(s.path, path);
+	assert_eq!(s.get::<u32>("var"), Some(3));
+	let manifest = read_to_string(format!("{}/.manifest", path)).unwrap();
+	assert!(manifest.contains("version: 1"));
+	assert!(!manifest.contains("somewhere"));
+	assert!(!manifest.contains("identifier"));
+	s.set("var", 4).unwrap();
+	drop(s);
+	assert_eq!(State::load_from("test_migration", &dir.path).unwrap().get::<u32>("var"), Some(4));
+	
+	//manifests from newer versions aren't guessed at
+	let newer = read_to_string(format!("{}/.manifest", path)).unwrap().replace("version: 1", "version: 99");
+	permissions::create_file(&format!("{}/.manifest", path)).unwrap().write_all(newer.as_bytes()).unwrap();
+	assert!(State::load_from("test_migration", &dir.path).is_err());
+}
+
+
+#[test]
+fn test_relocate() {
+	let dir = TempDir::new("test_relocate");
+	let other_dir = format!("{}/other", dir.path);
+	let open = |name: &str| State::builder(name).storage_dir(&dir.path).write_ahead_log(true).blob_threshold(16);
+	let mut s = open("test_relocate").create_if_missing(true).open().unwrap();
+	s.set("small", 1).unwrap();
+	s.set("big", "x".repeat(100)).unwrap();
+	
+	//not while it's open
+	assert!(State::copy_from("test_relocate", "test_relocate_copy", &dir.path, false).is_err());
+	drop(s);
+	
+	State::copy_from("test_relocate", "test_relocate_copy", &dir.path, false).unwrap();
+	#[cfg(unix)]
+	let inode = |name: &str| {
+		use std::os::unix::fs::MetadataExt;
+		metadata(format!("{}/{}/.manifest", dir.path, name)).unwrap().ino()
+	};
+	#[cfg(unix)]
+	let before = inode("test_relocate");
+	State::rename_from("test_relocate", "test_relocate_renamed", &dir.path, false).unwrap();
+	assert!(State::load_from("test_relocate", &dir.path).is_err());
+	//on the same filesystem, the state is renamed rather than copied
+	#[cfg(unix)]
+	assert_eq!(inode("test_relocate_renamed"), before);
+	for name in &["test_relocate_copy", "test_relocate_renamed"] {
+		let s = open(name).open().unwrap();
+		assert_eq!(s.get::<u32>("small"), Some(1));
+		assert_eq!(s.get::<String>("big"), Some("x".repeat(100)));
+	}
+	
+	//existing states are only replaced when asked
+	let mut s = State::new_from("test_relocate_copy", &other_dir).unwrap();
+	s.set("small", 2).unwrap();
+	drop(s);
+	assert!(State::move_to("test_relocate_copy", &dir.path, &other_dir, false).is_err());
+	assert_eq!(State::load_from("test_relocate_copy", &other_dir).unwrap().get::<u32>("small"), Some(2));
+	State::move_to("test_relocate_copy", &dir.path, &other_dir, true).unwrap();
+	assert!(!FsBackend::new(&dir.path).exists("test_relocate_copy").unwrap());
+	let s = open("test_relocate_copy").storage_dir(&other_dir).open().unwrap();
+	assert_eq!(s.get::<u32>("small"), Some(1));
+	assert_eq!(s.get::<String>("big"), Some("x".repeat(100)));
+	drop(s);
+	assert_eq!(read_dir(&other_dir).unwrap().count(), 1);
+	
+	//nor are directories that aren't states
+	let precious = format!("{}/test_relocate_precious/precious.txt", dir.path);
+	permissions::create_dir(&format!("{}/test_relocate_precious", dir.path)).unwrap();
+	permissions::create_file(&precious).unwrap();
+	assert!(State::rename_from("test_relocate_renamed", "test_relocate_precious", &dir.path, true).is_err());
+	assert!(Path::new(&precious).exists());
+	assert!(FsBackend::new(&dir.path).exists("test_relocate_renamed").unwrap());
+}
+
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_sqlite_backend() {
+	let dir = TempDir::new("test_sqlite");
+	let backend = Arc::new(SqliteBackend::open(&format!("{}/states.db", dir.path)).unwrap());
+	let open = || State::builder("test_sqlite").backend(backend.clone()).create_if_missing(true).open();
+	
+	let mut s = open().unwrap();
+	test_state(&mut s);
+	s.set("other_var", 17).unwrap();
+	assert!(open().is_err());
+	drop(s);
+	
+	let mut s = open().unwrap();
+	assert_eq!(s.get("test_var"), Some(String::from("foo")));
+	assert_eq!(s.get::<u32>("other_var"), Some(17));
+	//items are stored in their own rows, not in the manifest
+	let manifest = String::from_utf8(backend.read_manifest("test_sqlite").unwrap().unwrap()).unwrap();
+	assert!(!manifest.contains("other_var"));
+	assert_eq!(backend.read_items("test_sqlite").unwrap().len(), 2);
+	s.delete("other_var").unwrap();
+	assert_eq!(backend.read_items("test_sqlite").unwrap().len(), 1);
+	drop(s);
+	
+	//moving a state from the filesystem into the database
+	let fs_dir = format!("{}/fs", dir.path);
+	let mut s = State::new_from("test_sqlite_migrated", &fs_dir).unwrap();
+	s.set("var", 3).unwrap();
+	drop(s);
+	let s = State::builder("test_sqlite_migrated").backend(backend.clone()).migrate_from(&fs_dir).open().unwrap();
+	assert_eq!(s.get::<u32>("var"), Some(3));
+	assert!(!FsBackend::new(&fs_dir).exists("test_sqlite_migrated").unwrap());
+	assert_eq!(backend.list().unwrap(), vec![String::from("test_sqlite"), String::from("test_sqlite_migrated")]);
+}